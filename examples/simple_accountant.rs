@@ -3,6 +3,7 @@ extern crate sentry_usage_accountant;
 use clap::Parser;
 use sentry_usage_accountant::{KafkaConfig, UsageAccountant, UsageUnit};
 use std::collections::HashMap;
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -38,4 +39,9 @@ fn main() {
     accountant
         .record("my_resource", "yet_another_feature", 100, UsageUnit::Bytes)
         .unwrap();
+
+    let summary = accountant
+        .shutdown(Duration::from_secs(5))
+        .expect("failed to shut down the accountant");
+    println!("{:?}", summary);
 }
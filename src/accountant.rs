@@ -1,27 +1,20 @@
-use crate::{accumulator::UsageAccumulator, Producer};
-use chrono::{Duration, Utc};
+use crate::accumulator::{UsageAccumulator, UsageKey};
+use crate::report::FlushReportBuilder;
+use crate::{
+    AccountantError, Aggregation, BatchSizeLimits, CardinalityLimits, Clock, DeliveryStats,
+    FlushPolicy, FlushReport, OverflowPolicy, Producer, RetryPolicy, SystemClock, TimerKind,
+    TimestampPolicy, UnitRegistry, UsageSnapshot, UsageTimer, UsageUnit, WriteAheadLog,
+};
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
-use std::{fmt, ops::Drop};
-
-/// The unit of measures we support when recording usage.
-/// more can be added.
-#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "snake_case")]
-pub enum UsageUnit {
-    Milliseconds,
-    Bytes,
-    BytesSec,
-}
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Instant;
+use std::{mem, ops::Drop};
+use tracing::{event, Level};
 
-impl fmt::Display for UsageUnit {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            UsageUnit::Milliseconds => write!(f, "milliseconds"),
-            UsageUnit::Bytes => write!(f, "bytes"),
-            UsageUnit::BytesSec => write!(f, "bytes_sec"),
-        }
-    }
-}
+/// The default maximum number of tags a usage record can carry.
+pub const DEFAULT_MAX_TAGS: usize = 4;
 
 /// This is the entry point for the library. It is in most cases
 /// everything you need to instrument your application.
@@ -38,8 +31,18 @@ impl fmt::Display for UsageUnit {
 /// Avoid creating a UsageAccountant every time some data needs to
 /// be recorded.
 pub struct UsageAccountant<P: Producer> {
-    accumulator: UsageAccumulator,
-    producer: P,
+    pub(crate) accumulator: UsageAccumulator,
+    pub(crate) producer: P,
+    timestamp_policy: TimestampPolicy,
+    retry_policy: RetryPolicy,
+    summary: ShutdownSummary,
+    max_tags: usize,
+    pub(crate) units: Option<UnitRegistry>,
+    pub(crate) clock: Arc<dyn Clock>,
+    wal: Option<WriteAheadLog>,
+    /// Set once `shutdown` flushed the accountant for the last time, so
+    /// dropping it does not flush again.
+    shut_down: bool,
 }
 
 #[cfg(feature = "kafka")]
@@ -64,70 +67,675 @@ impl<P: Producer> UsageAccountant<P> {
         UsageAccountant {
             accumulator: UsageAccumulator::new(granularity),
             producer,
+            timestamp_policy: TimestampPolicy::default(),
+            retry_policy: RetryPolicy::default(),
+            summary: ShutdownSummary::default(),
+            max_tags: DEFAULT_MAX_TAGS,
+            units: None,
+            clock: Arc::new(SystemClock),
+            wal: None,
+            shut_down: false,
+        }
+    }
+
+    /// Sets the clock the current time is read from, the system clock
+    /// by default. Meant for tests, with a `MockClock`.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Sets how the amounts recorded with `unit` are combined within a
+    /// bucket. Units are summed unless configured otherwise.
+    pub fn with_aggregation(mut self, unit: UsageUnit, aggregation: Aggregation) -> Self {
+        self.accumulator.set_aggregation(unit, aggregation);
+        self
+    }
+
+    /// Makes the accountant convert usage to the canonical unit of its
+    /// dimension before accumulating it, so usage recorded in
+    /// compatible units is aggregated together. Custom units are
+    /// converted according to `units`.
+    ///
+    /// Recording fails with `UnitOverflow` if the converted amount does
+    /// not fit in a `u64`.
+    pub fn with_unit_normalization(mut self, units: UnitRegistry) -> Self {
+        self.units = Some(units);
+        self
+    }
+
+    /// Sets what happens when the amount accumulated for a key does
+    /// not fit in a `u64`. Amounts saturate by default.
+    pub fn with_overflow_policy(mut self, overflow_policy: OverflowPolicy) -> Self {
+        self.accumulator.set_overflow_policy(overflow_policy);
+        self
+    }
+
+    /// Returns how many times the amount accumulated for a key
+    /// overflowed since the accountant was created.
+    pub fn overflows(&self) -> u64 {
+        self.accumulator.overflows()
+    }
+
+    /// Caps the number of distinct keys in a batch, so a dynamic value
+    /// passed as app_feature or tag cannot flood the topic.
+    pub fn with_cardinality_limits(mut self, cardinality_limits: CardinalityLimits) -> Self {
+        self.accumulator.set_cardinality_limits(cardinality_limits);
+        self
+    }
+
+    /// Returns how many records hit a cardinality limit since the
+    /// accountant was created.
+    pub fn limited_records(&self) -> u64 {
+        self.accumulator.limited_records()
+    }
+
+    /// Returns a copy of the usage accumulated and not flushed yet,
+    /// without flushing it.
+    pub fn snapshot(&self) -> UsageSnapshot {
+        self.accumulator.snapshot()
+    }
+
+    /// Bounds the size of the batch, which is flushed as soon as it
+    /// reaches one of the limits instead of waiting for the
+    /// granularity to elapse.
+    ///
+    /// The limits are checked along with the flush policy, whichever
+    /// of the two is set first.
+    pub fn with_batch_size_limits(mut self, size_limits: BatchSizeLimits) -> Self {
+        self.accumulator.set_size_limits(size_limits);
+        self
+    }
+
+    /// Sets the policy deciding when the batch is flushed. By default
+    /// it is flushed once the bucket it was opened in is over.
+    ///
+    /// The batch size limits still apply, a batch reaching one of them
+    /// is flushed whatever the policy says.
+    pub fn with_flush_policy(mut self, flush_policy: impl FlushPolicy + 'static) -> Self {
+        self.accumulator.set_flush_policy(Arc::new(flush_policy));
+        self
+    }
+
+    /// Sets the maximum number of tags `record_with_tags` accepts.
+    ///
+    /// Every distinct tag value creates a new message per bucket, so
+    /// this protects the topic from a cardinality explosion.
+    pub fn with_max_tags(mut self, max_tags: usize) -> Self {
+        self.max_tags = max_tags;
+        self
+    }
+
+    /// Sets the policy deciding how long usage that could not be
+    /// produced is kept for later flushes.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Keeps the usage that was not delivered yet in a write-ahead log,
+    /// so it survives a crash of the application.
+    ///
+    /// The snapshot left by the previous instance of the application,
+    /// if any, is put back in the batch, which is flushed right away.
+    /// A failure to produce it is logged, and the usage kept for the
+    /// next flush. Recovered usage is retried until it is produced,
+    /// regardless of the `RetryPolicy`. Returns an error if the log
+    /// cannot be read.
+    ///
+    /// Call this after configuring the accountant, so the recovered
+    /// usage goes through its policies.
+    pub fn with_wal(mut self, mut wal: WriteAheadLog) -> Result<Self, AccountantError<P::Error>> {
+        let entries = wal.open().map_err(AccountantError::Wal)?;
+        let recovered = !entries.is_empty();
+        self.accumulator.restore(entries, self.clock.now());
+        self.producer.track_deliveries();
+        self.wal = Some(wal);
+        if recovered {
+            if let Err(error) = self.flush() {
+                event!(Level::ERROR, "Failed to flush recovered usage. {}", error);
+            }
         }
+        Ok(self)
+    }
+
+    /// Sets the policy applied to the timestamps passed to `record_at`.
+    pub fn with_timestamp_policy(mut self, timestamp_policy: TimestampPolicy) -> Self {
+        self.timestamp_policy = timestamp_policy;
+        self
     }
 
-    /// Records an mount of usage for a resource, and app_feature.
+    /// Records an amount of usage for a resource, and app_feature.
     ///
     /// It flushes the batch if that is ready to be flushed.
-    /// The timestamp used is the system timestamp.
+    /// The timestamp used is the system timestamp, the
+    /// `TimestampPolicy` does not apply.
     pub fn record(
         &mut self,
         resource_id: &str,
         app_feature: &str,
         amount: u64,
         unit: UsageUnit,
-    ) -> Result<(), P::Error> {
-        let current_time = Utc::now();
+    ) -> Result<(), AccountantError<P::Error>> {
+        let (amount, unit) = normalize(self.units.as_ref(), amount, unit)?;
+        let current_time = self.clock.now();
         self.accumulator
             .record(current_time, resource_id, app_feature, amount, unit);
-        if self.accumulator.should_flush(current_time) {
-            self.flush()?;
+        self.after_record(current_time)
+    }
+
+    /// Records an amount of usage for a resource, and app_feature
+    /// that happened at `timestamp`.
+    ///
+    /// This is meant for applications processing a backlog, which want
+    /// usage attributed to the time the work was produced. Timestamps
+    /// are validated against the `TimestampPolicy` of the accountant.
+    /// It flushes the batch if that is ready to be flushed.
+    pub fn record_at(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        resource_id: &str,
+        app_feature: &str,
+        amount: u64,
+        unit: UsageUnit,
+    ) -> Result<(), AccountantError<P::Error>> {
+        let (amount, unit) = normalize(self.units.as_ref(), amount, unit)?;
+        let current_time = self.clock.now();
+        if let Some(usage_time) = self.timestamp_policy.apply(timestamp, current_time) {
+            self.accumulator.record_received(
+                current_time,
+                usage_time,
+                resource_id,
+                app_feature,
+                amount,
+                unit,
+            );
         }
-        Ok(())
+        self.after_record(current_time)
+    }
+
+    /// Records an amount of usage for a resource, and app_feature
+    /// broken down by additional dimensions.
+    ///
+    /// Tags become part of the aggregation key, so usage is only
+    /// aggregated with usage carrying the same tags, and are included
+    /// in the produced message. The order of the tags does not matter,
+    /// if a name is repeated the last value wins.
+    /// Returns an error if more tags than allowed are passed.
+    pub fn record_with_tags(
+        &mut self,
+        resource_id: &str,
+        app_feature: &str,
+        amount: u64,
+        unit: UsageUnit,
+        tags: &[(&str, &str)],
+    ) -> Result<(), AccountantError<P::Error>> {
+        let (amount, unit) = normalize(self.units.as_ref(), amount, unit)?;
+        let current_time = self.clock.now();
+        let mut key = self
+            .accumulator
+            .key(current_time, resource_id, app_feature, unit);
+        key.tags = tag_set(tags, self.max_tags)?;
+        self.accumulator.add(current_time, key, amount);
+        self.after_record(current_time)
+    }
+
+    /// Records an amount of usage combined with `aggregation`, rather
+    /// than the aggregation configured for `unit`.
+    pub(crate) fn record_aggregated(
+        &mut self,
+        resource_id: &str,
+        app_feature: &str,
+        amount: u64,
+        unit: UsageUnit,
+        aggregation: Aggregation,
+    ) -> Result<(), AccountantError<P::Error>> {
+        let (amount, unit) = normalize(self.units.as_ref(), amount, unit)?;
+        let current_time = self.clock.now();
+        let mut key = self
+            .accumulator
+            .key(current_time, resource_id, app_feature, unit);
+        key.aggregation = aggregation;
+        self.accumulator.add(current_time, key, amount);
+        self.after_record(current_time)
+    }
+
+    /// Sets the number of bytes a resource, and app_feature currently
+    /// holds.
+    ///
+    /// Rather than recording byte-seconds themselves, applications
+    /// report the bytes held whenever they change. The accountant
+    /// integrates them over time and produces `BytesSec` usage for
+    /// every bucket until the gauge is set to zero.
+    /// It flushes the batch if that is ready to be flushed.
+    pub fn set_gauge(
+        &mut self,
+        resource_id: &str,
+        app_feature: &str,
+        bytes: u64,
+    ) -> Result<(), AccountantError<P::Error>> {
+        let current_time = self.clock.now();
+        self.accumulator.set_gauge(
+            current_time,
+            resource_id,
+            app_feature,
+            BTreeMap::new(),
+            bytes,
+        );
+        self.after_record(current_time)
+    }
+
+    /// Starts a timer that records the wall time elapsed, in
+    /// milliseconds, when it goes out of scope.
+    pub fn start_timer(&mut self, resource_id: &str, app_feature: &str) -> UsageTimer<'_, P> {
+        UsageTimer::wall(self, resource_id, app_feature)
+    }
+
+    /// Starts a timer that records the time elapsed when it goes out of
+    /// scope. `kind` decides whether wall time or the CPU time of the
+    /// current thread is measured.
+    ///
+    /// Fails with `AccountantError::Unsupported` if the platform cannot
+    /// measure that kind of time.
+    pub fn start_timer_with(
+        &mut self,
+        resource_id: &str,
+        app_feature: &str,
+        kind: TimerKind,
+    ) -> Result<UsageTimer<'_, P>, AccountantError<P::Error>> {
+        UsageTimer::new(self, resource_id, app_feature, kind)
     }
 
     /// Forces a flush of the existing batch.
     ///
     /// This method is called automatically when the Accountant
     /// goes out of scope.
-    pub fn flush(&mut self) -> Result<(), P::Error> {
-        let flushed_content = self.accumulator.flush();
-        for (key, amount) in flushed_content {
-            let message = Message {
-                timestamp: key.quantized_timestamp.timestamp(),
-                shared_resource_id: key.resource_id,
-                app_feature: key.app_feature,
-                usage_unit: key.unit,
-                amount,
-            };
+    ///
+    /// If the producer fails, the entries that were not produced are
+    /// put back in the accumulator, within the limits of the
+    /// `RetryPolicy`, and retried at the next flush.
+    ///
+    /// Returns a `FlushReport` describing the messages produced.
+    pub fn flush(&mut self) -> Result<FlushReport, AccountantError<P::Error>> {
+        let flushed_content = self.accumulator.flush_at(self.clock.now());
+        let mut outcome = BatchOutcome::new(flushed_content);
+        if self.wal.is_some() {
+            outcome = outcome.with_sent_entries();
+        }
+        let mut outcome = produce_batch(&mut self.producer, outcome);
+        if let Some(wal) = self.wal.as_mut() {
+            wal.sent(outcome.sent_entries.take().unwrap_or_default());
+            if let Err(error) = self.producer.drain(wal.delivery_timeout()) {
+                let error = AccountantError::Producer(error);
+                event!(
+                    Level::ERROR,
+                    "Failed to check the delivery of usage. {}",
+                    error
+                );
+            }
+            outcome.undelivered(wal.confirm(self.producer.take_deliveries()));
+        }
+        let retained = self.accumulator.retain(
+            mem::take(&mut outcome.unsent),
+            &self.retry_policy,
+            self.clock.now(),
+        );
+        self.summary.sent += outcome.sent as u64;
+        self.summary.lost += outcome.lost(retained) as u64;
+        self.write_wal();
+        outcome.into_result(retained)
+    }
+
+    /// Flushes the batch if it is ready. Returns `None` if it was not.
+    ///
+    /// Batches are only checked when usage is recorded, so an
+    /// application that may stop recording should call this
+    /// periodically, for example through a `BackgroundFlusher`.
+    pub fn flush_if_ready(&mut self) -> Result<Option<FlushReport>, AccountantError<P::Error>> {
+        if !self.accumulator.should_flush(self.clock.now()) {
+            return Ok(None);
+        }
+        self.flush().map(Some)
+    }
 
-            if let Ok(payload) = serde_json::to_vec(&message) {
-                self.producer.send(payload)?;
+    /// Flushes the batch if it is ready, otherwise writes a snapshot to
+    /// the write-ahead log if one is due.
+    fn after_record(
+        &mut self,
+        current_time: DateTime<Utc>,
+    ) -> Result<(), AccountantError<P::Error>> {
+        if self.accumulator.should_flush(current_time) {
+            self.flush()?;
+        } else if let Some(wal) = self
+            .wal
+            .as_mut()
+            .filter(|wal| wal.snapshot_due(current_time))
+        {
+            if let Err(error) = wal.write(self.accumulator.entries(), current_time) {
+                event!(Level::ERROR, "Failed to write usage snapshot. {}", error);
             }
         }
         Ok(())
     }
+
+    /// Rewrites the snapshot of the write-ahead log, if there is one.
+    fn write_wal(&mut self) {
+        let Some(wal) = self.wal.as_mut() else {
+            return;
+        };
+        if let Err(error) = wal.write(self.accumulator.entries(), self.clock.now()) {
+            event!(Level::ERROR, "Failed to write usage snapshot. {}", error);
+        }
+    }
+
+    /// Flushes the accountant for the last time and waits, for at most
+    /// `timeout`, for the producer to deliver everything it was handed.
+    ///
+    /// Unlike dropping the accountant, this reports what happened to
+    /// the usage recorded over the lifetime of the accountant. Usage
+    /// the producer refuses is retried, following the `RetryPolicy`,
+    /// once the producer is drained and until `timeout` expires. What
+    /// is still not produced then is counted as lost.
+    /// An error is only returned if the producer fails to drain.
+    ///
+    /// Open gauges are closed at the time of the shutdown, so their
+    /// usage is part of the last flush.
+    ///
+    /// With a write-ahead log, the usage that could not be delivered
+    /// stays in the log for the next instance of the application, and
+    /// is not counted as lost.
+    pub fn shutdown(
+        mut self,
+        timeout: std::time::Duration,
+    ) -> Result<ShutdownSummary, AccountantError<P::Error>> {
+        let deadline = Instant::now() + timeout;
+        self.shut_down = true;
+        self.accumulator.close_gauges(self.clock.now());
+        let mut retained = shutdown_flush(self.flush());
+        let mut delivery = self
+            .producer
+            .drain(timeout)
+            .map_err(AccountantError::Producer)?;
+        // Draining makes room in the queue of the producer, which is
+        // the most likely reason for it to refuse messages.
+        while retained > 0 && Instant::now() < deadline {
+            retained = shutdown_flush(self.flush());
+            delivery = self
+                .producer
+                .drain(deadline.saturating_duration_since(Instant::now()))
+                .map_err(AccountantError::Producer)?;
+        }
+        let mut remaining = self.accumulator.flush_at(self.clock.now());
+
+        // The log is taken so dropping the accountant does not rewrite
+        // it with an empty batch.
+        if let Some(mut wal) = self.wal.take() {
+            remaining.extend(wal.confirm(self.producer.take_deliveries()));
+            let entries = remaining.iter().map(|(key, amount)| (key, amount));
+            if let Err(error) = wal.write(entries, self.clock.now()) {
+                event!(Level::ERROR, "Failed to write usage snapshot. {}", error);
+            }
+        } else {
+            self.summary.lost += remaining.len() as u64;
+        }
+        Ok(self.summary.with_delivery(delivery))
+    }
+}
+
+/// Logs the failure of a flush made while shutting down, and returns
+/// how many entries were retained to be retried.
+pub(crate) fn shutdown_flush<E>(result: Result<FlushReport, AccountantError<E>>) -> usize {
+    let Err(error) = result else {
+        return 0;
+    };
+    event!(Level::ERROR, "Failed to flush usage on shutdown. {}", error);
+    match error {
+        AccountantError::PartialFlush { retained, .. } => retained,
+        _ => 0,
+    }
+}
+
+/// Builds the tags of a usage key, making sure there are at most
+/// `max_tags` of them.
+pub(crate) fn tag_set<E>(
+    tags: &[(&str, &str)],
+    max_tags: usize,
+) -> Result<BTreeMap<String, String>, AccountantError<E>> {
+    let tags: BTreeMap<String, String> = tags
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    if tags.len() > max_tags {
+        return Err(AccountantError::TooManyTags {
+            count: tags.len(),
+            max: max_tags,
+        });
+    }
+    Ok(tags)
+}
+
+/// Converts usage to the canonical unit of its dimension, if the
+/// accountant normalizes units.
+pub(crate) fn normalize<E>(
+    units: Option<&UnitRegistry>,
+    amount: u64,
+    unit: UsageUnit,
+) -> Result<(u64, UsageUnit), AccountantError<E>> {
+    match units {
+        Some(registry) => registry
+            .normalize(amount, unit.clone())
+            .ok_or(AccountantError::UnitOverflow { amount, unit }),
+        None => Ok((amount, unit)),
+    }
+}
+
+/// What happened to the usage recorded by an accountant over its
+/// lifetime. This is returned when shutting the accountant down.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ShutdownSummary {
+    /// Messages handed to the producer.
+    pub sent: u64,
+    /// Messages dropped by the accountant because they could not be
+    /// encoded or produced.
+    pub lost: u64,
+    /// Messages the producer confirmed as delivered.
+    pub delivered: u64,
+    /// Messages the producer accepted but failed to deliver.
+    pub failed: u64,
+    /// Messages still in flight when the shutdown timeout expired.
+    pub pending: u64,
+}
+
+impl ShutdownSummary {
+    /// Fills in the delivery outcome reported by the producer. Messages
+    /// sent through a producer that does not report it are considered
+    /// delivered.
+    pub(crate) fn with_delivery(self, delivery: Option<DeliveryStats>) -> Self {
+        let delivery = delivery.unwrap_or(DeliveryStats {
+            delivered: self.sent,
+            failed: 0,
+            pending: 0,
+        });
+        ShutdownSummary {
+            delivered: delivery.delivered,
+            failed: delivery.failed,
+            pending: delivery.pending,
+            ..self
+        }
+    }
+}
+
+/// Turns a flushed batch into messages and keeps track of what
+/// happened to them while they are handed to a producer.
+///
+/// This is shared by all the accountant flavors so they produce
+/// exactly the same messages. Messages that cannot be encoded are
+/// skipped, while a producer failure stops the flush and leaves the
+/// remaining entries unsent.
+pub(crate) struct BatchOutcome<E> {
+    pub(crate) total: usize,
+    pub(crate) sent: usize,
+    pub(crate) report: FlushReportBuilder,
+    /// Entries that could not be produced because of a producer
+    /// failure. They can be put back in the accumulator.
+    pub(crate) unsent: Vec<(UsageKey, u64)>,
+    /// Entries handed to the producer, in the order it accepted them,
+    /// if they are kept.
+    pub(crate) sent_entries: Option<Vec<(UsageKey, u64)>>,
+    pub(crate) failure: Option<AccountantError<E>>,
+    /// Entries not encoded yet.
+    entries: std::vec::IntoIter<(UsageKey, u64)>,
+    /// The entry whose payload is being sent.
+    current: Option<(UsageKey, u64)>,
+}
+
+impl<E> BatchOutcome<E> {
+    pub(crate) fn new(batch: Vec<(UsageKey, u64)>) -> Self {
+        BatchOutcome {
+            total: batch.len(),
+            sent: 0,
+            report: FlushReportBuilder::default(),
+            unsent: Vec::new(),
+            sent_entries: None,
+            failure: None,
+            entries: batch.into_iter(),
+            current: None,
+        }
+    }
+
+    /// Keeps the entries handed to the producer, which a write-ahead log
+    /// needs to track their delivery.
+    pub(crate) fn with_sent_entries(mut self) -> Self {
+        self.sent_entries = Some(Vec::new());
+        self
+    }
+
+    /// Returns the payload of the next message to send, or `None` once
+    /// the batch is over or the producer failed.
+    pub(crate) fn next_payload(&mut self) -> Option<Vec<u8>> {
+        for (key, amount) in self.entries.by_ref() {
+            match serde_json::to_vec(&Message::new(key.clone(), amount)) {
+                Ok(payload) => {
+                    self.current = Some((key, amount));
+                    return Some(payload);
+                }
+                Err(error) => {
+                    self.failure.get_or_insert(AccountantError::Encoding(error));
+                }
+            }
+        }
+        None
+    }
+
+    /// Accounts for the last payload being handed to the producer.
+    pub(crate) fn sent(&mut self, bytes: usize) {
+        if let Some((key, amount)) = self.current.take() {
+            self.sent += 1;
+            self.report.add(&key, amount, bytes);
+            if let Some(sent_entries) = &mut self.sent_entries {
+                sent_entries.push((key, amount));
+            }
+        }
+    }
+
+    /// Accounts for the producer failing to send the last payload,
+    /// which stops the flush.
+    pub(crate) fn failed(&mut self, error: E) {
+        self.failure.get_or_insert(AccountantError::Producer(error));
+        self.unsent.extend(self.current.take());
+        self.unsent.extend(self.entries.by_ref());
+    }
+
+    /// Adds entries of earlier flushes the producer failed to deliver
+    /// to the unsent ones, so they are retried along with them.
+    pub(crate) fn undelivered(&mut self, entries: Vec<(UsageKey, u64)>) {
+        self.total += entries.len();
+        self.unsent.extend(entries);
+    }
+
+    /// How many entries were dropped, once `retained` of the unsent
+    /// entries have been put back in the accumulator.
+    pub(crate) fn lost(&self, retained: usize) -> usize {
+        self.total - self.sent - retained
+    }
+
+    /// Turns the outcome into the result of a flush, once `retained`
+    /// of the unsent entries have been put back in the accumulator.
+    pub(crate) fn into_result(self, retained: usize) -> Result<FlushReport, AccountantError<E>> {
+        let lost = self.lost(retained);
+        match self.failure {
+            None => Ok(self.report.build()),
+            Some(source) => Err(AccountantError::PartialFlush {
+                sent: self.sent,
+                retained,
+                lost,
+                report: Box::new(self.report.build()),
+                source: Box::new(source),
+            }),
+        }
+    }
+}
+
+/// Turns the batch of `outcome` into messages and sends them through
+/// the producer.
+pub(crate) fn produce_batch<P: Producer>(
+    producer: &mut P,
+    mut outcome: BatchOutcome<P::Error>,
+) -> BatchOutcome<P::Error> {
+    while let Some(payload) = outcome.next_payload() {
+        let bytes = payload.len();
+        match producer.send(payload) {
+            Ok(()) => outcome.sent(bytes),
+            Err(error) => outcome.failed(error),
+        }
+    }
+    outcome
 }
 
 impl<P: Producer> Drop for UsageAccountant<P> {
     fn drop(&mut self) {
-        let _ = self.flush();
+        if !self.shut_down {
+            let _ = self.flush();
+        }
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct Message {
-    timestamp: i64,
-    shared_resource_id: String,
-    app_feature: String,
-    usage_unit: UsageUnit,
-    amount: u64,
+pub(crate) struct Message {
+    pub(crate) timestamp: i64,
+    pub(crate) shared_resource_id: String,
+    pub(crate) app_feature: String,
+    pub(crate) usage_unit: UsageUnit,
+    pub(crate) amount: u64,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) tags: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Aggregation::is_sum")]
+    pub(crate) aggregation: Aggregation,
+}
+
+impl Message {
+    pub(crate) fn new(key: UsageKey, amount: u64) -> Self {
+        Message {
+            timestamp: key.quantized_timestamp.timestamp(),
+            shared_resource_id: key.resource_id,
+            app_feature: key.app_feature,
+            usage_unit: key.unit,
+            amount,
+            tags: key.tags,
+            aggregation: key.aggregation,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::DummyProducer;
+    use std::collections::HashMap;
+
+    use chrono::TimeZone;
+
+    use crate::producer::DummyProducerError;
+    use crate::wal::TempDir;
+    use crate::{DeliveryReport, DummyProducer, MaxAge, MaxKeys, MockClock, OutOfRangeAction};
 
     use super::*;
 
@@ -149,11 +757,19 @@ mod tests {
         let res2 = accountant.record("resource_1", "spans", 200, UsageUnit::Bytes);
         assert!(res2.is_ok());
 
-        let res = accountant.flush();
-        assert!(res.is_ok());
+        let report = accountant.flush().unwrap();
+        assert_eq!(report.messages, 2);
+        assert_eq!(report.amounts, HashMap::from([(UsageUnit::Bytes, 300)]));
+        assert_eq!(report.resource_features, 2);
+        let (start, end) = report.time_range.unwrap();
+        assert_eq!(start, end);
 
         let messages = &accountant.producer.messages;
         assert_eq!(messages.len(), 2);
+        assert_eq!(
+            report.bytes,
+            messages.iter().map(|payload| payload.len()).sum::<usize>()
+        );
 
         let m1: Message = serde_json::from_slice(&messages[0]).unwrap();
         assert_eq!(m1.shared_resource_id, "resource_1");
@@ -178,4 +794,816 @@ mod tests {
         // Messages are still the same we had before the previous step.
         assert_eq!(accountant.producer.messages.len(), 2);
     }
+
+    #[test]
+    fn test_partial_flush() {
+        let mut accountant = UsageAccountant::new(
+            DummyProducer {
+                send_limit: Some(1),
+                ..Default::default()
+            },
+            None,
+        )
+        .with_retry_policy(RetryPolicy {
+            max_attempts: 0,
+            ..Default::default()
+        });
+        for feature in ["transactions", "spans", "profiles"] {
+            accountant
+                .record("resource_1", feature, 100, UsageUnit::Bytes)
+                .unwrap();
+        }
+
+        match accountant.flush() {
+            Err(AccountantError::PartialFlush {
+                sent,
+                retained,
+                lost,
+                report,
+                source,
+            }) => {
+                assert_eq!(sent, 1);
+                assert_eq!(retained, 0);
+                assert_eq!(lost, 2);
+                assert_eq!(report.messages, 1);
+                assert_eq!(report.amounts, HashMap::from([(UsageUnit::Bytes, 100)]));
+                assert!(matches!(*source, AccountantError::Producer(_)));
+            }
+            res => panic!("unexpected flush result {:?}", res),
+        }
+        assert_eq!(accountant.producer.messages.len(), 1);
+    }
+
+    #[test]
+    fn test_retain_unsent() {
+        let mut accountant = UsageAccountant::new(
+            DummyProducer {
+                send_limit: Some(1),
+                ..Default::default()
+            },
+            None,
+        );
+        for feature in ["transactions", "spans", "profiles"] {
+            accountant
+                .record("resource_1", feature, 100, UsageUnit::Bytes)
+                .unwrap();
+        }
+
+        match accountant.flush() {
+            Err(AccountantError::PartialFlush {
+                sent,
+                retained,
+                lost,
+                ..
+            }) => {
+                assert_eq!(sent, 1);
+                assert_eq!(retained, 2);
+                assert_eq!(lost, 0);
+            }
+            res => panic!("unexpected flush result {:?}", res),
+        }
+
+        // The producer recovers and the retained usage is produced.
+        accountant.producer.send_limit = None;
+        accountant.flush().unwrap();
+        let messages = &accountant.producer.messages;
+        assert_eq!(messages.len(), 3);
+        let mut features: Vec<String> = messages
+            .iter()
+            .map(|payload| {
+                serde_json::from_slice::<Message>(payload)
+                    .unwrap()
+                    .app_feature
+            })
+            .collect();
+        features.sort();
+        assert_eq!(features, ["profiles", "spans", "transactions"]);
+    }
+
+    #[test]
+    fn test_retain_old_usage() {
+        let start = Utc.with_ymd_and_hms(2023, 10, 8, 22, 15, 10).unwrap();
+        let clock = MockClock::new(start);
+        let producer = DummyProducer {
+            send_limit: Some(0),
+            ..Default::default()
+        };
+        let mut accountant = UsageAccountant::new(producer, None).with_clock(clock.clone());
+        accountant
+            .record_at(
+                start - Duration::minutes(30),
+                "resource_1",
+                "transactions",
+                100,
+                UsageUnit::Bytes,
+            )
+            .unwrap();
+
+        // The bucket is older than `max_age`, the usage is retried
+        // anyway as it only failed once.
+        match accountant.flush() {
+            Err(AccountantError::PartialFlush { retained, lost, .. }) => {
+                assert_eq!((retained, lost), (1, 0))
+            }
+            res => panic!("unexpected flush result {:?}", res),
+        }
+
+        accountant.producer.send_limit = None;
+        clock.advance(Duration::minutes(5));
+        accountant.flush().unwrap();
+        assert_eq!(accountant.producer.messages.len(), 1);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let mut accountant = UsageAccountant::new(
+            DummyProducer {
+                send_limit: Some(2),
+                ..Default::default()
+            },
+            None,
+        );
+        for feature in ["transactions", "spans", "profiles"] {
+            accountant
+                .record("resource_1", feature, 100, UsageUnit::Bytes)
+                .unwrap();
+        }
+
+        let summary = accountant
+            .shutdown(std::time::Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(
+            summary,
+            ShutdownSummary {
+                sent: 2,
+                lost: 1,
+                delivered: 2,
+                failed: 0,
+                pending: 0,
+            }
+        );
+    }
+
+    /// Moves forward by a second every time it is read.
+    struct TickingClock(std::sync::Mutex<DateTime<Utc>>);
+
+    impl Clock for TickingClock {
+        fn now(&self) -> DateTime<Utc> {
+            let mut now = self.0.lock().unwrap();
+            *now += Duration::seconds(1);
+            *now
+        }
+    }
+
+    #[test]
+    fn test_shutdown_closes_gauges() {
+        let start = Utc.with_ymd_and_hms(2023, 10, 8, 22, 15, 10).unwrap();
+        let mut producer = DummyProducer::default();
+        let mut accountant = UsageAccountant::new(&mut producer, None)
+            .with_clock(TickingClock(std::sync::Mutex::new(start)));
+        accountant
+            .set_gauge("resource_1", "cache", 1 << 40)
+            .unwrap();
+
+        let summary = accountant
+            .shutdown(std::time::Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(summary.lost, 0);
+        // Nothing is produced once the accountant is shut down.
+        assert_eq!(producer.messages.len() as u64, summary.sent);
+        assert_eq!(summary.sent, 1);
+    }
+
+    /// Queues up to `capacity` messages until it is drained.
+    #[derive(Default)]
+    struct QueueProducer {
+        capacity: usize,
+        queued: usize,
+        delivered: u64,
+    }
+
+    impl Producer for QueueProducer {
+        type Error = DummyProducerError;
+
+        fn send(&mut self, _payload: Vec<u8>) -> Result<(), Self::Error> {
+            if self.queued == self.capacity {
+                return Err(DummyProducerError);
+            }
+            self.queued += 1;
+            Ok(())
+        }
+
+        fn drain(
+            &mut self,
+            _timeout: std::time::Duration,
+        ) -> Result<Option<DeliveryStats>, Self::Error> {
+            self.delivered += mem::take(&mut self.queued) as u64;
+            Ok(Some(DeliveryStats {
+                delivered: self.delivered,
+                ..DeliveryStats::default()
+            }))
+        }
+    }
+
+    #[test]
+    fn test_shutdown_retries_after_draining() {
+        let mut accountant = UsageAccountant::new(
+            QueueProducer {
+                capacity: 2,
+                ..Default::default()
+            },
+            None,
+        );
+        for feature in ["transactions", "spans", "profiles"] {
+            accountant
+                .record("resource_1", feature, 100, UsageUnit::Bytes)
+                .unwrap();
+        }
+
+        let summary = accountant
+            .shutdown(std::time::Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(
+            summary,
+            ShutdownSummary {
+                sent: 3,
+                lost: 0,
+                delivered: 3,
+                failed: 0,
+                pending: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_record_with_tags() {
+        let mut accountant = UsageAccountant::new(DummyProducer::default(), None).with_max_tags(2);
+
+        accountant
+            .record_with_tags(
+                "resource_1",
+                "transactions",
+                100,
+                UsageUnit::Bytes,
+                &[("region", "us"), ("tier", "hot")],
+            )
+            .unwrap();
+        accountant
+            .record_with_tags(
+                "resource_1",
+                "transactions",
+                100,
+                UsageUnit::Bytes,
+                &[("tier", "hot"), ("region", "us")],
+            )
+            .unwrap();
+        accountant
+            .record("resource_1", "transactions", 100, UsageUnit::Bytes)
+            .unwrap();
+        let res = accountant.record_with_tags(
+            "resource_1",
+            "transactions",
+            100,
+            UsageUnit::Bytes,
+            &[("region", "us"), ("tier", "hot"), ("group", "a")],
+        );
+        assert!(matches!(
+            res,
+            Err(AccountantError::TooManyTags { count: 3, max: 2 })
+        ));
+
+        accountant.flush().unwrap();
+        let mut messages: Vec<Message> = accountant
+            .producer
+            .messages
+            .iter()
+            .map(|payload| serde_json::from_slice(payload).unwrap())
+            .collect();
+        messages.sort_by_key(|message| message.tags.len());
+        assert_eq!(messages.len(), 2);
+
+        assert_eq!(messages[0].amount, 100);
+        assert!(messages[0].tags.is_empty());
+        assert_eq!(messages[1].amount, 200);
+        assert_eq!(
+            messages[1].tags,
+            BTreeMap::from([
+                ("region".to_string(), "us".to_string()),
+                ("tier".to_string(), "hot".to_string()),
+            ])
+        );
+
+        // Messages without tags are unchanged.
+        let untagged = serde_json::to_value(&messages[0]).unwrap();
+        assert!(untagged.get("tags").is_none());
+    }
+
+    #[test]
+    fn test_aggregation() {
+        let mut accountant = UsageAccountant::new(DummyProducer::default(), None)
+            .with_aggregation(UsageUnit::BytesSec, Aggregation::Max);
+
+        for amount in [300, 500, 100] {
+            accountant
+                .record("resource_1", "transactions", amount, UsageUnit::BytesSec)
+                .unwrap();
+            accountant
+                .record("resource_1", "transactions", amount, UsageUnit::Bytes)
+                .unwrap();
+        }
+
+        accountant.flush().unwrap();
+        let mut messages: Vec<serde_json::Value> = accountant
+            .producer
+            .messages
+            .iter()
+            .map(|payload| serde_json::from_slice(payload).unwrap())
+            .collect();
+        messages.sort_by_key(|message| message["usage_unit"].as_str().unwrap().to_string());
+        assert_eq!(messages.len(), 2);
+
+        // Summed usage is produced as before.
+        assert_eq!(messages[0]["usage_unit"], "bytes");
+        assert_eq!(messages[0]["amount"], 900);
+        assert!(messages[0].get("aggregation").is_none());
+
+        assert_eq!(messages[1]["usage_unit"], "bytes_sec");
+        assert_eq!(messages[1]["amount"], 500);
+        assert_eq!(messages[1]["aggregation"], "max");
+    }
+
+    #[test]
+    fn test_mock_clock() {
+        let start = Utc.with_ymd_and_hms(2023, 10, 8, 22, 15, 10).unwrap();
+        let clock = MockClock::new(start);
+        let mut accountant =
+            UsageAccountant::new(DummyProducer::default(), None).with_clock(clock.clone());
+
+        accountant
+            .record("resource_1", "transactions", 100, UsageUnit::Bytes)
+            .unwrap();
+        clock.advance(Duration::seconds(45));
+        accountant
+            .record("resource_1", "transactions", 100, UsageUnit::Bytes)
+            .unwrap();
+        assert!(accountant.producer.messages.is_empty());
+
+        clock.advance(Duration::seconds(10));
+        accountant
+            .record("resource_1", "transactions", 100, UsageUnit::Bytes)
+            .unwrap();
+        let mut messages: Vec<Message> = accountant
+            .producer
+            .messages
+            .iter()
+            .map(|payload| serde_json::from_slice(payload).unwrap())
+            .collect();
+        messages.sort_by_key(|message| message.timestamp);
+        let amounts: Vec<_> = messages
+            .iter()
+            .map(|message| (message.timestamp, message.amount))
+            .collect();
+        let bucket = Utc.with_ymd_and_hms(2023, 10, 8, 22, 15, 0).unwrap();
+        assert_eq!(
+            amounts,
+            vec![
+                (bucket.timestamp(), 200),
+                ((bucket + Duration::minutes(1)).timestamp(), 100),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_wal_recovery() {
+        let dir = TempDir::new("wal-recovery");
+        let start = Utc.with_ymd_and_hms(2023, 10, 8, 22, 15, 10).unwrap();
+        let clock = MockClock::new(start);
+        let amounts = |producer: &DummyProducer| -> Vec<u64> {
+            producer
+                .messages
+                .iter()
+                .map(|payload| serde_json::from_slice::<Message>(payload).unwrap().amount)
+                .collect()
+        };
+
+        let mut accountant = UsageAccountant::new(DummyProducer::default(), None)
+            .with_clock(clock.clone())
+            .with_wal(WriteAheadLog::new(dir.path()))
+            .unwrap();
+        accountant
+            .record("resource_1", "transactions", 100, UsageUnit::Bytes)
+            .unwrap();
+        clock.advance(Duration::seconds(1));
+        // Recorded after the snapshot, so lost in the crash.
+        accountant
+            .record("resource_1", "transactions", 50, UsageUnit::Bytes)
+            .unwrap();
+        mem::forget(accountant);
+
+        let producer = DummyProducer {
+            send_limit: Some(0),
+            ..Default::default()
+        };
+        let accountant = UsageAccountant::new(producer, None)
+            .with_clock(clock.clone())
+            .with_wal(WriteAheadLog::new(dir.path()))
+            .unwrap();
+        assert!(accountant.producer.messages.is_empty());
+        // Kept in the log, not lost.
+        let summary = accountant.shutdown(std::time::Duration::ZERO).unwrap();
+        assert_eq!(summary.lost, 0);
+
+        let mut accountant = UsageAccountant::new(DummyProducer::default(), None)
+            .with_clock(clock.clone())
+            .with_wal(WriteAheadLog::new(dir.path()))
+            .unwrap();
+        assert_eq!(amounts(&accountant.producer), vec![100]);
+        accountant
+            .record("resource_1", "transactions", 10, UsageUnit::Bytes)
+            .unwrap();
+        accountant.shutdown(std::time::Duration::ZERO).unwrap();
+
+        let accountant = UsageAccountant::new(DummyProducer::default(), None)
+            .with_wal(WriteAheadLog::new(dir.path()))
+            .unwrap();
+        assert!(accountant.producer.messages.is_empty());
+    }
+
+    #[test]
+    fn test_wal_enforces_max_attempts() {
+        let dir = TempDir::new("wal-enforces-max-attempts");
+        let producer = DummyProducer {
+            send_limit: Some(0),
+            ..Default::default()
+        };
+        let mut accountant = UsageAccountant::new(producer, None)
+            .with_retry_policy(RetryPolicy {
+                max_attempts: 2,
+                max_age: Duration::minutes(10),
+            })
+            .with_wal(WriteAheadLog::new(dir.path()))
+            .unwrap();
+        accountant
+            .record("resource_1", "transactions", 100, UsageUnit::Bytes)
+            .unwrap();
+
+        for expected in [(1, 0), (1, 0), (0, 1)] {
+            match accountant.flush() {
+                Err(AccountantError::PartialFlush { retained, lost, .. }) => {
+                    assert_eq!((retained, lost), expected)
+                }
+                res => panic!("unexpected flush result {:?}", res),
+            }
+        }
+        assert!(accountant.snapshot().is_empty());
+        drop(accountant);
+    }
+
+    #[test]
+    fn test_wal_keeps_recovered_usage() {
+        let dir = TempDir::new("wal-keeps-recovered-usage");
+        let start = Utc.with_ymd_and_hms(2023, 10, 8, 22, 15, 10).unwrap();
+        let clock = MockClock::new(start);
+        let policy = RetryPolicy {
+            max_attempts: 2,
+            max_age: Duration::minutes(10),
+        };
+
+        let mut accountant = UsageAccountant::new(DummyProducer::default(), None)
+            .with_clock(clock.clone())
+            .with_wal(WriteAheadLog::new(dir.path()))
+            .unwrap();
+        accountant
+            .record("resource_1", "transactions", 100, UsageUnit::Bytes)
+            .unwrap();
+        mem::forget(accountant);
+
+        // Restarts long after the crash, with a failing producer.
+        clock.advance(Duration::minutes(15));
+        let producer = DummyProducer {
+            send_limit: Some(0),
+            ..Default::default()
+        };
+        let mut accountant = UsageAccountant::new(producer, None)
+            .with_clock(clock.clone())
+            .with_retry_policy(policy)
+            .with_wal(WriteAheadLog::new(dir.path()))
+            .unwrap();
+        for _ in 0..3 {
+            clock.advance(Duration::minutes(10));
+            assert!(accountant.flush().is_err());
+        }
+        assert_eq!(accountant.snapshot().entries.len(), 1);
+        assert_eq!(WriteAheadLog::new(dir.path()).open().unwrap().len(), 1);
+
+        accountant.producer.send_limit = None;
+        accountant.flush().unwrap();
+        assert_eq!(accountant.producer.messages.len(), 1);
+        assert!(WriteAheadLog::new(dir.path()).open().unwrap().is_empty());
+        drop(accountant);
+    }
+
+    /// Reports the deliveries it is given, one report per call.
+    #[derive(Default)]
+    struct ScriptedDeliveries {
+        messages: Vec<Vec<u8>>,
+        deliveries: Vec<DeliveryReport>,
+    }
+
+    impl Producer for ScriptedDeliveries {
+        type Error = DummyProducerError;
+
+        fn send(&mut self, payload: Vec<u8>) -> Result<(), Self::Error> {
+            self.messages.push(payload);
+            Ok(())
+        }
+
+        fn take_deliveries(&mut self) -> Option<DeliveryReport> {
+            Some(self.deliveries.pop().unwrap_or_default())
+        }
+    }
+
+    #[test]
+    fn test_wal_retries_failed_deliveries() {
+        let dir = TempDir::new("wal-retries-failed-deliveries");
+        let producer = ScriptedDeliveries {
+            // Popped from the end.
+            deliveries: vec![
+                DeliveryReport {
+                    delivered: vec![2],
+                    failed: vec![],
+                },
+                DeliveryReport {
+                    delivered: vec![1],
+                    failed: vec![0],
+                },
+                DeliveryReport::default(),
+            ],
+            ..Default::default()
+        };
+        let mut accountant = UsageAccountant::new(producer, None)
+            .with_wal(WriteAheadLog::new(dir.path()))
+            .unwrap();
+
+        accountant
+            .record("resource_1", "transactions", 100, UsageUnit::Bytes)
+            .unwrap();
+        accountant.flush().unwrap();
+        // Still pending.
+        assert!(accountant.snapshot().is_empty());
+        assert_eq!(WriteAheadLog::new(dir.path()).open().unwrap().len(), 1);
+
+        accountant
+            .record("resource_1", "spans", 50, UsageUnit::Bytes)
+            .unwrap();
+        accountant.flush().unwrap();
+        let snapshot = accountant.snapshot();
+        assert_eq!(snapshot.entries.len(), 1);
+        assert_eq!(snapshot.entries[0].0.app_feature, "transactions");
+
+        accountant.flush().unwrap();
+        assert!(accountant.snapshot().is_empty());
+        assert!(WriteAheadLog::new(dir.path()).open().unwrap().is_empty());
+        assert_eq!(accountant.producer.messages.len(), 3);
+        drop(accountant);
+    }
+
+    #[test]
+    fn test_flush_policy() {
+        let mut accountant =
+            UsageAccountant::new(DummyProducer::default(), None).with_flush_policy(MaxKeys(2));
+
+        accountant
+            .record("resource_1", "transactions", 100, UsageUnit::Bytes)
+            .unwrap();
+        assert!(accountant.producer.messages.is_empty());
+        accountant
+            .record("resource_1", "spans", 100, UsageUnit::Bytes)
+            .unwrap();
+        assert_eq!(accountant.producer.messages.len(), 2);
+    }
+
+    #[test]
+    fn test_size_limits_keep_flush_policy() {
+        let limits = BatchSizeLimits {
+            max_keys: 2,
+            ..BatchSizeLimits::default()
+        };
+        for limits_first in [true, false] {
+            let clock = MockClock::new(Utc.with_ymd_and_hms(2023, 10, 8, 22, 15, 10).unwrap());
+            // The bucket is not over before the policy flushes.
+            let accountant =
+                UsageAccountant::new(DummyProducer::default(), Some(Duration::hours(2)))
+                    .with_clock(clock.clone());
+            let mut accountant = if limits_first {
+                accountant
+                    .with_batch_size_limits(limits)
+                    .with_flush_policy(MaxAge(Duration::hours(1)))
+            } else {
+                accountant
+                    .with_flush_policy(MaxAge(Duration::hours(1)))
+                    .with_batch_size_limits(limits)
+            };
+
+            accountant
+                .record("resource_1", "transactions", 100, UsageUnit::Bytes)
+                .unwrap();
+            assert!(accountant.producer.messages.is_empty());
+            accountant
+                .record("resource_1", "spans", 100, UsageUnit::Bytes)
+                .unwrap();
+            assert_eq!(accountant.producer.messages.len(), 2);
+
+            accountant
+                .record("resource_1", "transactions", 100, UsageUnit::Bytes)
+                .unwrap();
+            clock.advance(Duration::hours(1));
+            accountant
+                .record("resource_1", "transactions", 100, UsageUnit::Bytes)
+                .unwrap();
+            assert_eq!(accountant.producer.messages.len(), 3);
+        }
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let start = Utc.with_ymd_and_hms(2023, 10, 8, 22, 15, 10).unwrap();
+        let clock = MockClock::new(start);
+        let mut accountant =
+            UsageAccountant::new(DummyProducer::default(), None).with_clock(clock.clone());
+        assert_eq!(accountant.snapshot(), UsageSnapshot::default());
+
+        accountant
+            .record("resource_1", "transactions", 100, UsageUnit::Bytes)
+            .unwrap();
+        accountant
+            .record_at(
+                start - Duration::minutes(2),
+                "resource_1",
+                "transactions",
+                50,
+                UsageUnit::Bytes,
+            )
+            .unwrap();
+        accountant
+            .record("resource_1", "transactions", 100, UsageUnit::Bytes)
+            .unwrap();
+
+        let snapshot = accountant.snapshot();
+        let bucket = Utc.with_ymd_and_hms(2023, 10, 8, 22, 15, 0).unwrap();
+        assert_eq!(
+            snapshot.buckets,
+            Some(bucket - Duration::minutes(2)..bucket + Duration::minutes(1))
+        );
+        let mut amounts: Vec<_> = snapshot
+            .entries
+            .iter()
+            .map(|(key, amount)| (key.quantized_timestamp, *amount))
+            .collect();
+        amounts.sort();
+        assert_eq!(
+            amounts,
+            vec![(bucket - Duration::minutes(2), 50), (bucket, 200)]
+        );
+
+        // Taking a snapshot does not clear the batch.
+        assert!(accountant.producer.messages.is_empty());
+        accountant.flush().unwrap();
+        assert_eq!(accountant.producer.messages.len(), 2);
+        assert!(accountant.snapshot().is_empty());
+    }
+
+    #[test]
+    fn test_overflow() {
+        for (policy, amounts) in [
+            (OverflowPolicy::Saturate, vec![u64::MAX]),
+            (OverflowPolicy::Split, vec![100, u64::MAX]),
+        ] {
+            let mut accountant =
+                UsageAccountant::new(DummyProducer::default(), None).with_overflow_policy(policy);
+            for amount in [u64::MAX - 100, 100, 100] {
+                accountant
+                    .record("resource_1", "transactions", amount, UsageUnit::BytesSec)
+                    .unwrap();
+            }
+            assert_eq!(accountant.overflows(), 1);
+
+            accountant.flush().unwrap();
+            let mut produced: Vec<u64> = accountant
+                .producer
+                .messages
+                .iter()
+                .map(|payload| serde_json::from_slice::<Message>(payload).unwrap().amount)
+                .collect();
+            produced.sort();
+            assert_eq!(produced, amounts);
+        }
+    }
+
+    #[test]
+    fn test_unit_normalization() {
+        let mut accountant = UsageAccountant::new(DummyProducer::default(), None)
+            .with_unit_normalization(UnitRegistry::new());
+
+        accountant
+            .record("resource_1", "transactions", 2, UsageUnit::Milliseconds)
+            .unwrap();
+        accountant
+            .record("resource_1", "transactions", 500, UsageUnit::Microseconds)
+            .unwrap();
+        let res = accountant.record(
+            "resource_1",
+            "transactions",
+            u64::MAX,
+            UsageUnit::Milliseconds,
+        );
+        assert!(matches!(
+            res,
+            Err(AccountantError::UnitOverflow {
+                amount: u64::MAX,
+                unit: UsageUnit::Milliseconds,
+            })
+        ));
+
+        accountant.flush().unwrap();
+        assert_eq!(accountant.producer.messages.len(), 1);
+        let message: Message = serde_json::from_slice(&accountant.producer.messages[0]).unwrap();
+        assert_eq!(message.usage_unit, UsageUnit::Nanoseconds);
+        assert_eq!(message.amount, 2_500_000);
+    }
+
+    #[test]
+    fn test_record_ignores_timestamp_policy() {
+        let clock = MockClock::new(Utc.with_ymd_and_hms(2023, 10, 8, 22, 15, 10).unwrap());
+        // Drops the current time, only usage from before is accepted.
+        let mut accountant = UsageAccountant::new(DummyProducer::default(), None)
+            .with_clock(clock.clone())
+            .with_timestamp_policy(TimestampPolicy {
+                max_age: Duration::hours(1),
+                max_future: Duration::minutes(-1),
+                action: OutOfRangeAction::Drop,
+            });
+
+        accountant
+            .record("resource_1", "transactions", 100, UsageUnit::Bytes)
+            .unwrap();
+        accountant
+            .record_at(
+                clock.now(),
+                "resource_1",
+                "transactions",
+                50,
+                UsageUnit::Bytes,
+            )
+            .unwrap();
+
+        accountant.flush().unwrap();
+        let messages = &accountant.producer.messages;
+        assert_eq!(messages.len(), 1);
+        let message: Message = serde_json::from_slice(&messages[0]).unwrap();
+        assert_eq!(message.amount, 100);
+    }
+
+    #[test]
+    fn test_record_at() {
+        let mut accountant = UsageAccountant::new(DummyProducer::default(), None)
+            .with_timestamp_policy(TimestampPolicy {
+                max_age: Duration::hours(1),
+                max_future: Duration::minutes(1),
+                action: OutOfRangeAction::Drop,
+            });
+
+        let timestamp = Utc::now() - Duration::minutes(30);
+        accountant
+            .record_at(
+                timestamp,
+                "resource_1",
+                "transactions",
+                100,
+                UsageUnit::Bytes,
+            )
+            .unwrap();
+        accountant
+            .record_at(
+                Utc::now() - Duration::hours(2),
+                "resource_1",
+                "spans",
+                100,
+                UsageUnit::Bytes,
+            )
+            .unwrap();
+        // Old usage does not make the batch ready to be flushed.
+        assert_eq!(accountant.producer.messages.len(), 0);
+
+        accountant.flush().unwrap();
+        let messages = &accountant.producer.messages;
+        assert_eq!(messages.len(), 1);
+
+        let m1: Message = serde_json::from_slice(&messages[0]).unwrap();
+        assert_eq!(m1.app_feature, "transactions");
+        assert_eq!(
+            m1.timestamp,
+            timestamp.timestamp() - timestamp.timestamp() % 60
+        );
+    }
 }
@@ -6,23 +6,86 @@
 //!
 
 use chrono::{DateTime, Duration, DurationRound, Utc};
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
 use std::mem;
+use std::sync::{Arc, Mutex};
 
-use crate::UsageUnit;
+use tracing::{event, Level};
 
+use crate::cardinality::KeyBudget;
+use crate::lock::lock;
+use crate::{
+    Aggregation, BatchSizeLimits, BatchState, BucketBoundary, CardinalityLimits, FlushPolicy,
+    GranularityMismatch, OverLimitAction, OverflowPolicy, RetryPolicy, UsageSnapshot, UsageUnit,
+};
+
+/// Identifies the usage aggregated together: all the amounts recorded
+/// with the same key within a bucket become one message.
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub struct UsageKey {
     pub quantized_timestamp: DateTime<Utc>,
     pub resource_id: String,
     pub app_feature: String,
     pub unit: UsageUnit,
+    /// Extra dimensions, ordered by name.
+    pub tags: BTreeMap<String, String>,
+    /// How the amounts recorded under this key are combined.
+    pub aggregation: Aggregation,
 }
 
+/// Pre-aggregates the usage recorded by an accountant until it is
+/// flushed.
+///
+/// The accountants own one and take care of flushing it, applications
+/// only need it to build their own accountant.
 pub struct UsageAccumulator {
     usage_batch: HashMap<UsageKey, u64>,
     granularity: Duration,
+    /// When the first usage of the batch was recorded.
     first_timestamp: Option<DateTime<Utc>>,
+    flush_policy: Arc<dyn FlushPolicy>,
+    size_limits: BatchSizeLimits,
+    /// The retries of the entries that were put back in the batch.
+    retries: HashMap<UsageKey, Retry>,
+    /// The aggregation of each unit that is not summed.
+    aggregations: HashMap<UsageUnit, Aggregation>,
+    /// The last sample of each gauge holding a non zero amount of
+    /// bytes, by resource, app_feature and tags.
+    gauges: HashMap<(String, String, BTreeMap<String, String>), GaugeSample>,
+    overflow_policy: OverflowPolicy,
+    /// Full amounts split off keys that overflowed, produced as
+    /// separate messages.
+    spilled: Vec<(UsageKey, u64)>,
+    overflows: u64,
+    cardinality_limits: CardinalityLimits,
+    /// Number of keys of each resource in the batch.
+    resource_keys: HashMap<String, usize>,
+    /// The keys counted against the cardinality limits, possibly along
+    /// with the keys of other accumulators.
+    key_budget: Arc<Mutex<KeyBudget>>,
+    /// When the last cardinality warning was logged for each resource.
+    limit_warnings: HashMap<String, DateTime<Utc>>,
+    limited_records: u64,
+    /// Estimated heap memory taken by the batch.
+    estimated_bytes: usize,
+}
+
+/// How many flushes failed for an entry put back in the batch, since
+/// when it is retried.
+#[derive(Clone, Copy)]
+struct Retry {
+    attempts: u32,
+    since: DateTime<Utc>,
+    /// Entries recovered from a write-ahead log are retried until they
+    /// are produced, as dropping them would drop them from the log.
+    restored: bool,
+}
+
+/// How many bytes a gauge holds since when.
+struct GaugeSample {
+    since: DateTime<Utc>,
+    bytes: u64,
 }
 
 impl UsageAccumulator {
@@ -33,6 +96,87 @@ impl UsageAccumulator {
             usage_batch: HashMap::new(),
             granularity: granularity.unwrap_or(Duration::seconds(60)),
             first_timestamp: None,
+            flush_policy: Arc::new(BucketBoundary),
+            size_limits: BatchSizeLimits::default(),
+            retries: HashMap::new(),
+            aggregations: HashMap::new(),
+            gauges: HashMap::new(),
+            overflow_policy: OverflowPolicy::default(),
+            spilled: Vec::new(),
+            overflows: 0,
+            cardinality_limits: CardinalityLimits::default(),
+            resource_keys: HashMap::new(),
+            key_budget: Arc::default(),
+            limit_warnings: HashMap::new(),
+            limited_records: 0,
+            estimated_bytes: 0,
+        }
+    }
+
+    /// Returns the size of the buckets usage is accumulated in.
+    pub fn granularity(&self) -> Duration {
+        self.granularity
+    }
+
+    /// Creates an empty accumulator with the same granularity,
+    /// aggregations and overflow policy, so the keys it builds can be
+    /// merged back into this one.
+    pub(crate) fn empty_like(&self) -> UsageAccumulator {
+        let mut accumulator = UsageAccumulator::new(Some(self.granularity));
+        accumulator.aggregations = self.aggregations.clone();
+        accumulator.overflow_policy = self.overflow_policy;
+        accumulator
+    }
+
+    /// Sets the policy deciding when the batch is ready to be flushed.
+    /// The size limits still apply.
+    pub fn set_flush_policy(&mut self, flush_policy: Arc<dyn FlushPolicy>) {
+        self.flush_policy = flush_policy;
+    }
+
+    /// Sets the limits on the size of the batch, checked along with
+    /// the flush policy.
+    pub fn set_size_limits(&mut self, size_limits: BatchSizeLimits) {
+        self.size_limits = size_limits;
+    }
+
+    /// Sets the maximum number of distinct keys in a batch.
+    pub fn set_cardinality_limits(&mut self, cardinality_limits: CardinalityLimits) {
+        self.cardinality_limits = cardinality_limits;
+    }
+
+    /// Enforces the cardinality limits on the keys of all the
+    /// accumulators sharing `key_budget`, instead of on this one alone.
+    pub(crate) fn share_key_budget(&mut self, key_budget: Arc<Mutex<KeyBudget>>) {
+        self.key_budget = key_budget;
+    }
+
+    /// Returns how many records hit a cardinality limit since the
+    /// accumulator was created.
+    pub fn limited_records(&self) -> u64 {
+        self.limited_records
+    }
+
+    /// Sets what happens when the amount of a key overflows.
+    pub fn set_overflow_policy(&mut self, overflow_policy: OverflowPolicy) {
+        self.overflow_policy = overflow_policy;
+    }
+
+    /// Returns how many times the amount of a key overflowed since the
+    /// accumulator was created.
+    pub fn overflows(&self) -> u64 {
+        self.overflows
+    }
+
+    /// Sets how the amounts recorded with `usage_unit` are combined.
+    /// Units are summed unless configured otherwise.
+    ///
+    /// Only affects usage recorded afterwards.
+    pub fn set_aggregation(&mut self, usage_unit: UsageUnit, aggregation: Aggregation) {
+        if aggregation.is_sum() {
+            self.aggregations.remove(&usage_unit);
+        } else {
+            self.aggregations.insert(usage_unit, aggregation);
         }
     }
 
@@ -51,54 +195,572 @@ impl UsageAccumulator {
         amount: u64,
         usage_unit: UsageUnit,
     ) {
-        // Check for zero here because of chrono bug, which causes a panic:
-        // https://github.com/chronotope/chrono/pull/1474
-        let quantized_timestamp = if self.granularity.is_zero() {
-            usage_time
-        } else {
-            usage_time.duration_trunc(self.granularity).unwrap()
-        };
+        self.record_received(
+            usage_time,
+            usage_time,
+            resource_id,
+            app_feature,
+            amount,
+            usage_unit,
+        )
+    }
 
-        if self.first_timestamp.is_none() {
-            self.first_timestamp = Some(quantized_timestamp);
-        }
+    /// Records an amount of usage that happened at `usage_time` but is
+    /// only being recorded at `received_time`.
+    ///
+    /// Usage is bucketed by `usage_time`, while the age of the batch,
+    /// which decides when it is flushed, is measured from
+    /// `received_time`. This way recording a backlog of old usage does
+    /// not make every record flush the batch.
+    pub(crate) fn record_received(
+        &mut self,
+        received_time: DateTime<Utc>,
+        usage_time: DateTime<Utc>,
+        resource_id: &str,
+        app_feature: &str,
+        amount: u64,
+        usage_unit: UsageUnit,
+    ) {
+        let key = self.key(usage_time, resource_id, app_feature, usage_unit);
+        self.add(received_time, key, amount);
+    }
 
-        let key = UsageKey {
-            quantized_timestamp,
+    /// Returns the key, without tags, usage that happened at
+    /// `usage_time` is accumulated under.
+    pub(crate) fn key(
+        &self,
+        usage_time: DateTime<Utc>,
+        resource_id: &str,
+        app_feature: &str,
+        usage_unit: UsageUnit,
+    ) -> UsageKey {
+        let aggregation = self
+            .aggregations
+            .get(&usage_unit)
+            .copied()
+            .unwrap_or_default();
+        UsageKey {
+            quantized_timestamp: self.quantize(usage_time),
             resource_id: resource_id.to_string(),
             app_feature: app_feature.to_string(),
             unit: usage_unit,
+            tags: BTreeMap::new(),
+            aggregation,
+        }
+    }
+
+    /// Adds an amount of usage to a key built by `key`.
+    ///
+    /// `received_time` is the time the usage is recorded, which
+    /// decides when the batch is flushed.
+    pub(crate) fn add(&mut self, received_time: DateTime<Utc>, key: UsageKey, amount: u64) {
+        let Some(key) = self.admit(received_time, key) else {
+            return;
+        };
+        if self.first_timestamp.is_none() {
+            self.first_timestamp = Some(received_time);
+        }
+
+        let aggregation = key.aggregation;
+        let value = match self.usage_batch.get(&key) {
+            Some(value) => {
+                let result = aggregation.record(*value, amount);
+                self.settle(&key, result)
+            }
+            None => aggregation.initial(amount),
         };
+        self.store(key, value);
+    }
 
-        let value = self.usage_batch.entry(key).or_default();
-        *value += amount;
+    /// Sets the value of a key, keeping track of the memory taken by
+    /// the batch.
+    fn store(&mut self, key: UsageKey, value: u64) {
+        if !self.usage_batch.contains_key(&key) {
+            self.estimated_bytes += estimated_size(&key);
+        }
+        self.usage_batch.insert(key, value);
+    }
+
+    /// Returns the key usage has to be recorded under according to the
+    /// cardinality limits, or `None` if it has to be dropped.
+    ///
+    /// The `OverLimitAction::Other` key of a resource is always
+    /// admitted, so it can exceed the limits by one key per bucket and
+    /// unit.
+    fn admit(&mut self, current_time: DateTime<Utc>, key: UsageKey) -> Option<UsageKey> {
+        if self.usage_batch.contains_key(&key) {
+            return Some(key);
+        }
+        if self.try_count_key(&key.resource_id) {
+            return Some(key);
+        }
+
+        let limits = &self.cardinality_limits;
+        self.limited_records += 1;
+        let warn = match self.limit_warnings.get(&key.resource_id) {
+            Some(last_warning) => current_time - *last_warning >= limits.warning_interval,
+            None => true,
+        };
+        if warn {
+            event!(
+                Level::WARN,
+                "Too many usage keys for resource {}, at most {} per resource and {} overall",
+                key.resource_id,
+                limits.max_keys_per_resource,
+                limits.max_keys,
+            );
+            self.limit_warnings
+                .insert(key.resource_id.clone(), current_time);
+        }
+
+        match &limits.action {
+            OverLimitAction::Drop => None,
+            OverLimitAction::Other(app_feature) => {
+                let other = UsageKey {
+                    app_feature: app_feature.clone(),
+                    tags: BTreeMap::new(),
+                    ..key
+                };
+                // The key is admitted over the limits, but only counted
+                // against them while it is within them.
+                if !self.usage_batch.contains_key(&other) {
+                    self.try_count_key(&other.resource_id);
+                }
+                Some(other)
+            }
+        }
+    }
+
+    /// Counts a new key of `resource_id` against the cardinality limits
+    /// if it is within them. Returns false if it is not.
+    fn try_count_key(&mut self, resource_id: &str) -> bool {
+        let admitted = lock(&self.key_budget).try_admit(&self.cardinality_limits, resource_id);
+        if admitted {
+            self.count_local_key(resource_id);
+        }
+        admitted
+    }
+
+    /// Counts a new key of `resource_id` against the cardinality
+    /// limits, even if it is over them.
+    fn count_key(&mut self, resource_id: &str) {
+        lock(&self.key_budget).add(resource_id);
+        self.count_local_key(resource_id);
+    }
+
+    /// Counts a key of `resource_id` among the keys of this batch, to
+    /// release it from the budget once flushed.
+    fn count_local_key(&mut self, resource_id: &str) {
+        *self
+            .resource_keys
+            .entry(resource_id.to_owned())
+            .or_default() += 1;
+    }
+
+    /// Turns the result of combining two amounts into the value of
+    /// `key`, applying the overflow policy if that overflowed.
+    fn settle(&mut self, key: &UsageKey, (value, overflowed): (u64, bool)) -> u64 {
+        if !overflowed {
+            return value;
+        }
+        self.overflows += 1;
+        match self.overflow_policy {
+            OverflowPolicy::Saturate => u64::MAX,
+            OverflowPolicy::Split => {
+                self.spilled.push((key.clone(), u64::MAX));
+                // The sum is `u64::MAX + value + 1`, this cannot
+                // overflow again as both terms were at most `u64::MAX`.
+                value + 1
+            }
+        }
+    }
+
+    /// Sets the number of bytes a resource, app_feature and tags
+    /// tuple holds from `sample_time` on.
+    ///
+    /// The bytes held since the previous sample are integrated over
+    /// time and accumulated as `BytesSec`, summed, in the buckets the
+    /// interval spans. The interval is split at bucket boundaries so
+    /// each bucket only gets the byte-seconds held within it. Setting
+    /// a gauge to zero stops integrating it.
+    pub fn set_gauge(
+        &mut self,
+        sample_time: DateTime<Utc>,
+        resource_id: &str,
+        app_feature: &str,
+        tags: BTreeMap<String, String>,
+        bytes: u64,
+    ) {
+        let gauge = (resource_id.to_string(), app_feature.to_string(), tags);
+        let since = match self.gauges.remove(&gauge) {
+            Some(previous) => {
+                self.integrate(&gauge, &previous, sample_time);
+                previous.since.max(sample_time)
+            }
+            None => sample_time,
+        };
+
+        if bytes > 0 {
+            if self.first_timestamp.is_none() {
+                self.first_timestamp = Some(sample_time);
+            }
+            self.gauges.insert(gauge, GaugeSample { since, bytes });
+        }
+    }
+
+    /// Accumulates the byte-seconds held by a gauge between the time
+    /// of its sample and `until`.
+    fn integrate(
+        &mut self,
+        (resource_id, app_feature, tags): &(String, String, BTreeMap<String, String>),
+        sample: &GaugeSample,
+        until: DateTime<Utc>,
+    ) {
+        let mut start = sample.since;
+        while start < until {
+            let bucket = self.quantize(start);
+            let end = if self.granularity.is_zero() {
+                until
+            } else {
+                until.min(bucket + self.granularity)
+            };
+            let millis = (end - start).num_milliseconds().max(0) as u128;
+            let amount = u64::try_from(sample.bytes as u128 * millis / 1000).unwrap_or(u64::MAX);
+
+            let mut key = self.key(start, resource_id, app_feature, UsageUnit::BytesSec);
+            key.tags = tags.clone();
+            key.aggregation = Aggregation::Sum;
+            start = end;
+            // Short intervals of few bytes round down to nothing, which
+            // is not worth a key.
+            if amount == 0 {
+                continue;
+            }
+            let Some(key) = self.admit(until, key) else {
+                continue;
+            };
+            let value = self.usage_batch.get(&key).copied().unwrap_or_default();
+            let value = self.settle(&key, value.overflowing_add(amount));
+            self.store(key, value);
+        }
+    }
+
+    fn quantize(&self, timestamp: DateTime<Utc>) -> DateTime<Utc> {
+        quantize(timestamp, self.granularity)
     }
 
     /// Returns true if the bucket is ready to be flushed.
     ///
     /// Ready to be flushed means that the bucket is not empty
-    /// and at least `granularity` seconds have passed since
-    /// the first chunk of data was added.
+    /// and either the flush policy says so, by default once the
+    /// bucket the first chunk of data was added in is over, or
+    /// the batch reached one of its size limits.
     pub fn should_flush(&self, current_time: DateTime<Utc>) -> bool {
         let Some(first_timestamp) = self.first_timestamp else {
             return false;
         };
+        if self.usage_batch.is_empty() && self.gauges.is_empty() {
+            return false;
+        }
 
-        self.usage_batch.keys().len() > 0 && current_time - first_timestamp >= self.granularity
+        let batch = BatchState {
+            keys: self.usage_batch.len(),
+            estimated_bytes: self.estimated_bytes,
+            opened_at: first_timestamp,
+            granularity: self.granularity,
+        };
+        self.size_limits.reached(&batch) || self.flush_policy.should_flush(&batch, current_time)
     }
 
     /// Return the current bucket and clears up the state.
-    pub fn flush(&mut self) -> HashMap<UsageKey, u64> {
+    ///
+    /// Amounts split off keys that overflowed and open gauges are left
+    /// alone, `flush_at` is the way to flush everything.
+    fn flush(&mut self) -> HashMap<UsageKey, u64> {
         self.first_timestamp = None;
+        lock(&self.key_budget).release(&self.resource_keys);
+        self.resource_keys.clear();
+        self.estimated_bytes = 0;
         mem::take(&mut self.usage_batch)
     }
+
+    /// Integrates the open gauges up to `current_time`, then returns
+    /// the current bucket, including the amounts split off keys that
+    /// overflowed, and clears up the state.
+    ///
+    /// Gauges stay open, so the following batch starts aging right
+    /// away.
+    pub fn flush_at(&mut self, current_time: DateTime<Utc>) -> Vec<(UsageKey, u64)> {
+        let gauges = mem::take(&mut self.gauges);
+        for (gauge, sample) in &gauges {
+            self.integrate(gauge, sample, current_time);
+        }
+        let mut batch = mem::take(&mut self.spilled);
+        batch.extend(self.flush());
+
+        if !gauges.is_empty() {
+            self.first_timestamp = Some(current_time);
+        }
+        self.gauges = gauges
+            .into_iter()
+            .map(|(gauge, sample)| {
+                let since = sample.since.max(current_time);
+                (gauge, GaugeSample { since, ..sample })
+            })
+            .collect();
+        batch
+    }
+
+    /// Integrates the open gauges up to `current_time` and stops
+    /// integrating them, as if they were all set to zero.
+    ///
+    /// This is meant for shutting down, so the last flush includes the
+    /// gauges and nothing is left to integrate afterwards.
+    pub(crate) fn close_gauges(&mut self, current_time: DateTime<Utc>) {
+        for (gauge, sample) in mem::take(&mut self.gauges) {
+            self.integrate(&gauge, &sample, current_time);
+        }
+    }
+
+    /// Puts back in the batch the entries of the last flush that could
+    /// not be produced, so they are retried at the next flush.
+    ///
+    /// Entries that already failed `max_attempts` flushes or that are
+    /// retried for longer than `max_age` are dropped. Returns how many
+    /// entries were put back. This has to be called after every
+    /// flush, even with no entries, to reset the retry counts of the
+    /// entries that were produced.
+    pub(crate) fn retain(
+        &mut self,
+        entries: Vec<(UsageKey, u64)>,
+        policy: &RetryPolicy,
+        current_time: DateTime<Utc>,
+    ) -> usize {
+        let previous_retries = mem::take(&mut self.retries);
+        let mut retained = 0;
+        for (key, amount) in entries {
+            let retry = match previous_retries.get(&key) {
+                Some(retry) => Retry {
+                    attempts: retry.attempts + 1,
+                    ..*retry
+                },
+                None => Retry {
+                    attempts: 1,
+                    since: current_time,
+                    restored: false,
+                },
+            };
+            let expired =
+                retry.attempts > policy.max_attempts || current_time - retry.since > policy.max_age;
+            if expired && !retry.restored {
+                continue;
+            }
+
+            self.retries.insert(key.clone(), retry);
+            self.merge_older(current_time, key, amount);
+            retained += 1;
+        }
+        retained
+    }
+
+    /// Puts back in the batch entries recovered from a write-ahead log.
+    ///
+    /// They are retried until they are produced, whatever the
+    /// `RetryPolicy`.
+    pub(crate) fn restore(&mut self, entries: Vec<(UsageKey, u64)>, current_time: DateTime<Utc>) {
+        for (key, amount) in entries {
+            let retry = Retry {
+                attempts: 0,
+                since: current_time,
+                restored: true,
+            };
+            self.retries.insert(key.clone(), retry);
+            self.merge_older(current_time, key, amount);
+        }
+    }
+
+    /// Merges an amount aggregated before the current batch started
+    /// into it.
+    ///
+    /// Those keys were admitted once already, so they do not go
+    /// through the cardinality limits again.
+    fn merge_older(&mut self, current_time: DateTime<Utc>, key: UsageKey, amount: u64) {
+        // The batch age restarts so the retry happens after another
+        // `granularity` instead of at the very next record.
+        if self.first_timestamp.is_none() {
+            self.first_timestamp = Some(current_time);
+        }
+        // Whatever was recorded since is newer than `amount`.
+        let value = match self.usage_batch.get(&key) {
+            Some(value) => {
+                let result = key.aggregation.merge(amount, *value);
+                self.settle(&key, result)
+            }
+            None => {
+                self.count_key(&key.resource_id);
+                amount
+            }
+        };
+        self.store(key, value);
+    }
+
+    /// Moves the usage accumulated by `other` into this accumulator.
+    ///
+    /// Granularities are checked and usage re-quantized like
+    /// `drain_into` does. Amounts of keys present on both sides are
+    /// combined according to their aggregation, the ones of `other`
+    /// being the newer. Open gauges of `other` are integrated up to
+    /// `current_time` and keep being integrated here, unless this
+    /// accumulator already has them open.
+    pub fn merge(
+        &mut self,
+        mut other: UsageAccumulator,
+        current_time: DateTime<Utc>,
+    ) -> Result<(), GranularityMismatch> {
+        other.drain_into(self, current_time)?;
+        for (gauge, sample) in other.gauges {
+            if let Entry::Vacant(entry) = self.gauges.entry(gauge) {
+                entry.insert(sample);
+                // The gauge is integrated up to now, the batch holding
+                // what comes next starts aging now.
+                self.first_timestamp.get_or_insert(current_time);
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the usage accumulated so far into `target`, leaving this
+    /// accumulator empty. Open gauges are integrated up to
+    /// `current_time` and stay open.
+    ///
+    /// The granularity of `target` has to be the same as this one, or a
+    /// multiple of it, in which case usage is re-quantized into the
+    /// coarser buckets of `target`. Otherwise buckets would not line up,
+    /// so nothing is moved and an error is returned. Moved usage goes
+    /// through the cardinality limits of `target`.
+    pub fn drain_into(
+        &mut self,
+        target: &mut UsageAccumulator,
+        current_time: DateTime<Utc>,
+    ) -> Result<(), GranularityMismatch> {
+        if !is_multiple(target.granularity, self.granularity) {
+            return Err(GranularityMismatch {
+                from: self.granularity,
+                into: target.granularity,
+            });
+        }
+        let opened_at = self.first_timestamp.unwrap_or(current_time);
+        for (key, amount) in self.flush_at(current_time) {
+            target.absorb(opened_at, current_time, key, amount);
+        }
+        Ok(())
+    }
+
+    /// Returns when the first usage of the batch was recorded, if the
+    /// batch is open.
+    pub(crate) fn opened_at(&self) -> Option<DateTime<Utc>> {
+        self.first_timestamp
+    }
+
+    /// Combines an amount aggregated by another accumulator, in a batch
+    /// opened at `opened_at`, with the batch.
+    pub(crate) fn absorb(
+        &mut self,
+        opened_at: DateTime<Utc>,
+        current_time: DateTime<Utc>,
+        mut key: UsageKey,
+        amount: u64,
+    ) {
+        key.quantized_timestamp = self.quantize(key.quantized_timestamp);
+        let Some(key) = self.admit(current_time, key) else {
+            return;
+        };
+        // The batch ages from the oldest usage it holds.
+        self.first_timestamp = Some(match self.first_timestamp {
+            Some(first_timestamp) => first_timestamp.min(opened_at),
+            None => opened_at,
+        });
+
+        let value = match self.usage_batch.get(&key) {
+            Some(value) => {
+                let result = key.aggregation.merge(*value, amount);
+                self.settle(&key, result)
+            }
+            None => amount,
+        };
+        self.store(key, value);
+    }
+
+    /// Returns a copy of the usage accumulated, without clearing it.
+    ///
+    /// Open gauges only contribute the byte-seconds integrated up to
+    /// their last sample.
+    pub fn snapshot(&self) -> UsageSnapshot {
+        let entries = self
+            .entries()
+            .map(|(key, amount)| (key.clone(), *amount))
+            .collect();
+        UsageSnapshot::new(entries, self.granularity)
+    }
+
+    /// Iterates over the entries of the batch, including the amounts
+    /// split off keys that overflowed.
+    pub(crate) fn entries(&self) -> impl Iterator<Item = (&UsageKey, &u64)> {
+        self.spilled
+            .iter()
+            .map(|(key, amount)| (key, amount))
+            .chain(self.usage_batch.iter())
+    }
+}
+
+/// Returns true if buckets of `granularity` are made of whole buckets
+/// of `finer`.
+fn is_multiple(granularity: Duration, finer: Duration) -> bool {
+    if granularity == finer || finer.is_zero() {
+        return true;
+    }
+    match (granularity.num_nanoseconds(), finer.num_nanoseconds()) {
+        (Some(granularity), Some(finer)) => granularity > 0 && granularity % finer == 0,
+        _ => false,
+    }
+}
+
+pub(crate) fn quantize(timestamp: DateTime<Utc>, granularity: Duration) -> DateTime<Utc> {
+    // Check for zero here because of chrono bug, which causes a panic:
+    // https://github.com/chronotope/chrono/pull/1474
+    if granularity.is_zero() {
+        timestamp
+    } else {
+        timestamp.duration_trunc(granularity).unwrap()
+    }
+}
+
+/// A rough estimate of the heap memory a key and its value take in
+/// the batch, ignoring the overhead of the map.
+fn estimated_size(key: &UsageKey) -> usize {
+    let custom_unit = match &key.unit {
+        UsageUnit::Custom(name) => name.len(),
+        _ => 0,
+    };
+    let tags: usize = key
+        .tags
+        .iter()
+        .map(|(name, value)| mem::size_of::<(String, String)>() + name.len() + value.len())
+        .sum();
+    mem::size_of::<(UsageKey, u64)>()
+        + key.resource_id.len()
+        + key.app_feature.len()
+        + custom_unit
+        + tags
 }
 
 #[cfg(test)]
 mod tests {
     use super::{UsageAccumulator, UsageKey, UsageUnit};
-    use chrono::{TimeZone, Utc};
-    use std::collections::HashMap;
+    use crate::{Aggregation, BatchSizeLimits, CardinalityLimits, OverLimitAction, RetryPolicy};
+    use chrono::{Duration, TimeZone, Utc};
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::Arc;
 
     #[test]
     fn empty_batch() {
@@ -138,6 +800,8 @@ mod tests {
                     resource_id: "genericmetrics_consumer".to_string(),
                     app_feature: "transactions".to_string(),
                     unit: UsageUnit::Milliseconds,
+                    tags: BTreeMap::new(),
+                    aggregation: Aggregation::Sum,
                 },
                 100,
             ),
@@ -147,6 +811,8 @@ mod tests {
                     resource_id: "genericmetrics_consumer".to_string(),
                     app_feature: "spans".to_string(),
                     unit: UsageUnit::Milliseconds,
+                    tags: BTreeMap::new(),
+                    aggregation: Aggregation::Sum,
                 },
                 200,
             ),
@@ -190,6 +856,8 @@ mod tests {
                     resource_id: "genericmetrics_consumer".to_string(),
                     app_feature: "transactions".to_string(),
                     unit: UsageUnit::Milliseconds,
+                    tags: BTreeMap::new(),
+                    aggregation: Aggregation::Sum,
                 },
                 200,
             ),
@@ -199,6 +867,8 @@ mod tests {
                     resource_id: "genericmetrics_consumer".to_string(),
                     app_feature: "transactions".to_string(),
                     unit: UsageUnit::Milliseconds,
+                    tags: BTreeMap::new(),
+                    aggregation: Aggregation::Sum,
                 },
                 100,
             ),
@@ -208,4 +878,308 @@ mod tests {
         let message = accumulator.flush();
         assert_eq!(message.keys().len(), 0);
     }
+
+    #[test]
+    fn test_merge_accumulators() {
+        let at = |min, sec| Utc.with_ymd_and_hms(2023, 10, 8, 22, min, sec).unwrap();
+        let mut accumulator = UsageAccumulator::new(None);
+        accumulator.set_aggregation(UsageUnit::Bytes, Aggregation::Max);
+        accumulator.record(
+            at(15, 10),
+            "resource_1",
+            "transactions",
+            100,
+            UsageUnit::Bytes,
+        );
+
+        let mut local = accumulator.empty_like();
+        local.record(
+            at(15, 20),
+            "resource_1",
+            "transactions",
+            200,
+            UsageUnit::Bytes,
+        );
+        local.record(at(15, 20), "resource_1", "spans", 10, UsageUnit::Bytes);
+        accumulator.merge(local, at(15, 30)).unwrap();
+
+        let batch = accumulator.flush();
+        assert_eq!(batch.len(), 2);
+        let key = accumulator.key(at(15, 0), "resource_1", "transactions", UsageUnit::Bytes);
+        assert_eq!(batch.get(&key), Some(&200));
+
+        let mut seconds = UsageAccumulator::new(Some(Duration::seconds(10)));
+        seconds.record(
+            at(15, 10),
+            "resource_1",
+            "transactions",
+            100,
+            UsageUnit::Bytes,
+        );
+        seconds.record(
+            at(15, 50),
+            "resource_1",
+            "transactions",
+            100,
+            UsageUnit::Bytes,
+        );
+        let mut odd = UsageAccumulator::new(Some(Duration::seconds(15)));
+        assert!(odd.merge(seconds, at(15, 55)).is_err());
+        assert!(odd.flush().is_empty());
+
+        let mut seconds = UsageAccumulator::new(Some(Duration::seconds(10)));
+        seconds.record(
+            at(15, 10),
+            "resource_1",
+            "transactions",
+            100,
+            UsageUnit::Bytes,
+        );
+        seconds.record(
+            at(15, 50),
+            "resource_1",
+            "transactions",
+            100,
+            UsageUnit::Bytes,
+        );
+        let mut odd = UsageAccumulator::new(Some(Duration::seconds(15)));
+        assert!(seconds.drain_into(&mut odd, at(15, 55)).is_err());
+        assert!(odd.flush().is_empty());
+
+        let mut minutes = UsageAccumulator::new(None);
+        seconds.drain_into(&mut minutes, at(15, 55)).unwrap();
+        assert!(seconds.flush().is_empty());
+        let batch = minutes.flush();
+        let key = minutes.key(at(15, 0), "resource_1", "transactions", UsageUnit::Bytes);
+        assert_eq!(batch, HashMap::from([(key.clone(), 200)]));
+
+        // Merging re-quantizes too.
+        let mut seconds = UsageAccumulator::new(Some(Duration::seconds(10)));
+        seconds.record(
+            at(15, 10),
+            "resource_1",
+            "transactions",
+            100,
+            UsageUnit::Bytes,
+        );
+        minutes.merge(seconds, at(15, 55)).unwrap();
+        assert_eq!(minutes.flush(), HashMap::from([(key, 100)]));
+
+        // An open gauge moved into an empty accumulator opens its batch.
+        let mut seconds = UsageAccumulator::new(Some(Duration::seconds(10)));
+        seconds.set_gauge(at(15, 50), "resource_1", "storage", BTreeMap::new(), 1000);
+        minutes.merge(seconds, at(15, 50)).unwrap();
+        assert!(!minutes.should_flush(at(15, 55)));
+        assert!(minutes.should_flush(at(16, 0)));
+    }
+
+    #[test]
+    fn test_retain() {
+        let policy = RetryPolicy {
+            max_attempts: 2,
+            max_age: Duration::minutes(10),
+        };
+        let key = UsageKey {
+            quantized_timestamp: Utc.with_ymd_and_hms(2023, 10, 8, 22, 15, 0).unwrap(),
+            resource_id: "genericmetrics_consumer".to_string(),
+            app_feature: "transactions".to_string(),
+            unit: UsageUnit::Milliseconds,
+            tags: BTreeMap::new(),
+            aggregation: Aggregation::Sum,
+        };
+        let mut accumulator = UsageAccumulator::new(None);
+        let now = Utc.with_ymd_and_hms(2023, 10, 8, 22, 16, 5).unwrap();
+
+        for _ in 0..2 {
+            let retained = accumulator.retain(vec![(key.clone(), 100)], &policy, now);
+            assert_eq!(retained, 1);
+            assert!(!accumulator.should_flush(now));
+            assert_eq!(accumulator.flush(), HashMap::from([(key.clone(), 100)]));
+        }
+
+        // The retry budget is exhausted.
+        assert_eq!(
+            accumulator.retain(vec![(key.clone(), 100)], &policy, now),
+            0
+        );
+        assert_eq!(accumulator.flush().len(), 0);
+
+        // A successful flush resets the budget, but the entry can
+        // still be retried for too long.
+        accumulator.retain(vec![], &policy, now);
+        assert_eq!(
+            accumulator.retain(vec![(key.clone(), 100)], &policy, now),
+            1
+        );
+        accumulator.flush();
+        let later = Utc.with_ymd_and_hms(2023, 10, 8, 22, 30, 0).unwrap();
+        assert_eq!(accumulator.retain(vec![(key, 100)], &policy, later), 0);
+    }
+
+    #[test]
+    fn test_record_received_late() {
+        let mut accumulator = UsageAccumulator::new(None);
+        accumulator.record_received(
+            Utc.with_ymd_and_hms(2023, 10, 8, 22, 15, 25).unwrap(),
+            Utc.with_ymd_and_hms(2023, 10, 8, 20, 10, 25).unwrap(),
+            "genericmetrics_consumer",
+            "transactions",
+            100,
+            UsageUnit::Milliseconds,
+        );
+
+        // The batch age is measured from when the data was received.
+        assert!(!accumulator.should_flush(Utc.with_ymd_and_hms(2023, 10, 8, 22, 15, 45).unwrap()));
+        assert!(accumulator.should_flush(Utc.with_ymd_and_hms(2023, 10, 8, 22, 16, 0).unwrap()));
+
+        let ret = accumulator.flush();
+        let test_val = HashMap::from([(
+            UsageKey {
+                quantized_timestamp: Utc.with_ymd_and_hms(2023, 10, 8, 20, 10, 0).unwrap(),
+                resource_id: "genericmetrics_consumer".to_string(),
+                app_feature: "transactions".to_string(),
+                unit: UsageUnit::Milliseconds,
+                tags: BTreeMap::new(),
+                aggregation: Aggregation::Sum,
+            },
+            100,
+        )]);
+        assert_eq!(ret, test_val);
+    }
+
+    #[test]
+    fn test_gauge() {
+        let mut accumulator = UsageAccumulator::new(None);
+        let at = |min, sec| Utc.with_ymd_and_hms(2023, 10, 8, 22, min, sec).unwrap();
+        let bytes_sec = |batch: &[(UsageKey, u64)], min| {
+            batch
+                .iter()
+                .find(|(key, _)| key.quantized_timestamp == at(min, 0))
+                .map(|(key, amount)| {
+                    assert_eq!(key.unit, UsageUnit::BytesSec);
+                    *amount
+                })
+        };
+
+        accumulator.set_gauge(at(0, 30), "resource_1", "storage", BTreeMap::new(), 1000);
+        accumulator.set_gauge(at(1, 15), "resource_1", "storage", BTreeMap::new(), 2000);
+        let batch = accumulator.flush_at(at(1, 30));
+        assert_eq!(batch.len(), 2);
+        assert_eq!(bytes_sec(&batch[..], 0), Some(30_000));
+        assert_eq!(bytes_sec(&batch[..], 1), Some(15_000 + 30_000));
+
+        // The gauge is still open, it keeps being integrated.
+        assert!(!accumulator.should_flush(at(1, 45)));
+        assert!(accumulator.should_flush(at(2, 30)));
+        let batch = accumulator.flush_at(at(2, 30));
+        assert_eq!(bytes_sec(&batch[..], 1), Some(60_000));
+        assert_eq!(bytes_sec(&batch[..], 2), Some(60_000));
+
+        accumulator.set_gauge(at(2, 40), "resource_1", "storage", BTreeMap::new(), 0);
+        let batch = accumulator.flush_at(at(3, 0));
+        assert_eq!(batch.len(), 1);
+        assert_eq!(bytes_sec(&batch[..], 2), Some(20_000));
+        assert!(!accumulator.should_flush(at(5, 0)));
+    }
+
+    #[test]
+    fn test_gauge_skips_zero_amounts() {
+        let at = |millis| {
+            Utc.with_ymd_and_hms(2023, 10, 8, 22, 15, 0).unwrap() + Duration::milliseconds(millis)
+        };
+        let mut accumulator = UsageAccumulator::new(None);
+        accumulator.set_cardinality_limits(CardinalityLimits {
+            max_keys: 1,
+            ..CardinalityLimits::default()
+        });
+
+        accumulator.set_gauge(at(0), "resource_1", "storage", BTreeMap::new(), 1);
+        assert!(accumulator.flush_at(at(500)).is_empty());
+        // The empty key did not take the only key of the batch.
+        accumulator.record(at(600), "resource_1", "spans", 10, UsageUnit::Bytes);
+        assert_eq!(accumulator.limited_records(), 0);
+        assert_eq!(accumulator.flush_at(at(700)).len(), 1);
+    }
+
+    #[test]
+    fn test_size_limits() {
+        let timestamp = Utc.with_ymd_and_hms(2023, 10, 8, 22, 15, 25).unwrap();
+        let mut accumulator = UsageAccumulator::new(None);
+        let limits = BatchSizeLimits {
+            max_keys: 2,
+            ..BatchSizeLimits::default()
+        };
+        accumulator.set_flush_policy(Arc::new(limits.flush_policy()));
+        accumulator.record(timestamp, "resource_1", "a", 10, UsageUnit::Bytes);
+        accumulator.record(timestamp, "resource_1", "a", 10, UsageUnit::Bytes);
+        assert!(!accumulator.should_flush(timestamp));
+        accumulator.record(timestamp, "resource_1", "b", 10, UsageUnit::Bytes);
+        assert!(accumulator.should_flush(timestamp));
+        assert_eq!(accumulator.flush().len(), 2);
+
+        let limits = BatchSizeLimits {
+            max_bytes: 1,
+            ..BatchSizeLimits::default()
+        };
+        accumulator.set_flush_policy(Arc::new(limits.flush_policy()));
+        assert!(!accumulator.should_flush(timestamp));
+        accumulator.record(timestamp, "resource_1", "a", 10, UsageUnit::Bytes);
+        assert!(accumulator.should_flush(timestamp));
+    }
+
+    #[test]
+    fn test_cardinality_limits() {
+        let timestamp = Utc.with_ymd_and_hms(2023, 10, 8, 22, 15, 25).unwrap();
+        let other = OverLimitAction::Other("__other__".to_string());
+        let cases = [
+            // The `__other__` key of resource_1 is over the limits, so
+            // it does not take a key from resource_2.
+            (
+                other,
+                3,
+                vec![
+                    ("resource_1", "__other__", 20),
+                    ("resource_1", "a", 20),
+                    ("resource_1", "b", 10),
+                    ("resource_2", "__other__", 10),
+                    ("resource_2", "a", 10),
+                    ("resource_2", "b", 10),
+                ],
+            ),
+            (
+                OverLimitAction::Drop,
+                3,
+                vec![
+                    ("resource_1", "a", 20),
+                    ("resource_1", "b", 10),
+                    ("resource_2", "a", 10),
+                    ("resource_2", "b", 10),
+                ],
+            ),
+        ];
+        for (action, limited, expected) in cases {
+            let mut accumulator = UsageAccumulator::new(None);
+            accumulator.set_cardinality_limits(CardinalityLimits {
+                max_keys: 4,
+                max_keys_per_resource: 2,
+                action,
+                ..CardinalityLimits::default()
+            });
+            for feature in ["a", "b", "c", "a", "d"] {
+                accumulator.record(timestamp, "resource_1", feature, 10, UsageUnit::Bytes);
+            }
+            for feature in ["a", "b", "c"] {
+                accumulator.record(timestamp, "resource_2", feature, 10, UsageUnit::Bytes);
+            }
+            assert_eq!(accumulator.limited_records(), limited);
+
+            let batch = accumulator.flush();
+            let mut batch: Vec<_> = batch
+                .iter()
+                .map(|(key, amount)| (key.resource_id.as_str(), key.app_feature.as_str(), *amount))
+                .collect();
+            batch.sort();
+            assert_eq!(batch, expected);
+        }
+    }
 }
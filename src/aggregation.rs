@@ -0,0 +1,119 @@
+//! This module contains the ways usage recorded in the same bucket
+//! can be aggregated.
+//!
+//! Summing is right for most resources, but it is meaningless for
+//! samples of a gauge, like the memory held by a pod, where the peak
+//! or the latest value is what matters.
+//!
+
+use serde::{Deserialize, Serialize};
+
+/// How the amounts recorded for the same key in the same bucket are
+/// combined into the amount of the produced message.
+///
+/// The aggregation is included in the message, unless it is `Sum`, so
+/// consumers know how to interpret the amount.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregation {
+    /// The sum of the amounts recorded.
+    #[default]
+    Sum,
+    /// The largest amount recorded.
+    Max,
+    /// The smallest amount recorded.
+    Min,
+    /// The last amount recorded.
+    Last,
+    /// How many times usage was recorded, whatever the amount.
+    Count,
+}
+
+impl Aggregation {
+    pub(crate) fn is_sum(&self) -> bool {
+        *self == Aggregation::Sum
+    }
+
+    /// The value of a key after the first amount is recorded.
+    pub(crate) fn initial(&self, amount: u64) -> u64 {
+        match self {
+            Aggregation::Count => 1,
+            _ => amount,
+        }
+    }
+
+    /// Adds a newly recorded amount to the current value of a key.
+    ///
+    /// Like `u64::overflowing_add`, returns the wrapped value and
+    /// whether it overflowed.
+    pub(crate) fn record(&self, value: u64, amount: u64) -> (u64, bool) {
+        match self {
+            Aggregation::Sum => value.overflowing_add(amount),
+            Aggregation::Max => (value.max(amount), false),
+            Aggregation::Min => (value.min(amount), false),
+            Aggregation::Last => (amount, false),
+            Aggregation::Count => value.overflowing_add(1),
+        }
+    }
+
+    /// Combines two already aggregated values of the same key, where
+    /// `newer` was aggregated after `older`. Overflows like `record`.
+    pub(crate) fn merge(&self, older: u64, newer: u64) -> (u64, bool) {
+        match self {
+            Aggregation::Sum | Aggregation::Count => older.overflowing_add(newer),
+            Aggregation::Max => (older.max(newer), false),
+            Aggregation::Min => (older.min(newer), false),
+            Aggregation::Last => (newer, false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aggregate(aggregation: Aggregation, amounts: &[u64]) -> u64 {
+        let (first, rest) = amounts.split_first().unwrap();
+        rest.iter()
+            .fold(aggregation.initial(*first), |value, amount| {
+                aggregation.record(value, *amount).0
+            })
+    }
+
+    #[test]
+    fn test_aggregations() {
+        let amounts = [30, 10, 50, 20];
+        assert_eq!(aggregate(Aggregation::Sum, &amounts), 110);
+        assert_eq!(aggregate(Aggregation::Max, &amounts), 50);
+        assert_eq!(aggregate(Aggregation::Min, &amounts), 10);
+        assert_eq!(aggregate(Aggregation::Last, &amounts), 20);
+        assert_eq!(aggregate(Aggregation::Count, &amounts), 4);
+    }
+
+    #[test]
+    fn test_merge_matches_record() {
+        let (older, newer) = ([30, 10], [50, 20]);
+        for aggregation in [
+            Aggregation::Sum,
+            Aggregation::Max,
+            Aggregation::Min,
+            Aggregation::Last,
+            Aggregation::Count,
+        ] {
+            assert_eq!(
+                aggregation.merge(
+                    aggregate(aggregation, &older),
+                    aggregate(aggregation, &newer)
+                ),
+                (aggregate(aggregation, &[30, 10, 50, 20]), false),
+            );
+        }
+    }
+
+    #[test]
+    fn test_overflow() {
+        assert_eq!(Aggregation::Sum.record(u64::MAX, 2), (1, true));
+        assert_eq!(Aggregation::Max.record(u64::MAX, 2), (u64::MAX, false));
+        assert_eq!(Aggregation::Count.merge(u64::MAX, 1), (0, true));
+    }
+}
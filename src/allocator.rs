@@ -0,0 +1,661 @@
+//! This module contains a global allocator attributing heap memory to
+//! the feature active on the allocating thread.
+//!
+//! Memory is a shared resource like any other, but allocations happen
+//! everywhere, so recording them by hand is not an option. Installing
+//! an `AccountingAllocator` as the global allocator counts the bytes
+//! allocated and freed while a feature scope is active, and an
+//! `AllocationReporter` turns the counters into usage.
+//!
+
+use std::alloc::{GlobalAlloc, Layout};
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::mem;
+use std::sync::atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering};
+
+use crate::{AccountantError, Aggregation, Producer, UsageAccountant, UsageUnit};
+
+/// The maximum number of features an `AccountingAllocator` tracks.
+pub const MAX_FEATURES: usize = 64;
+
+/// The number of threads getting counters of their own in an
+/// `AccountingAllocator`.
+pub const THREAD_COUNTERS: usize = 64;
+
+/// Marks the allocations made outside of any feature scope.
+const NO_FEATURE: usize = usize::MAX;
+
+/// Marks a thread that did not count any allocation yet.
+const NO_THREAD_INDEX: usize = usize::MAX;
+
+/// The index of the counters the next thread counting an allocation
+/// gets. Indices are never reused, so the counters of a thread are
+/// only ever written by that thread.
+static NEXT_THREAD_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static ACTIVE_FEATURE: Cell<usize> = const { Cell::new(NO_FEATURE) };
+    static THREAD_INDEX: Cell<usize> = const { Cell::new(NO_THREAD_INDEX) };
+}
+
+/// Identifies a feature tracked by an `AccountingAllocator`. Ids are
+/// handed out by `AccountingAllocator::register`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct FeatureId(usize);
+
+impl FeatureId {
+    /// Attributes the allocations of the current thread to this
+    /// feature until the returned guard is dropped. Scopes nest, the
+    /// previous feature is active again once the guard is dropped.
+    pub fn enter(self) -> FeatureScope {
+        let previous = ACTIVE_FEATURE.with(|active| active.replace(self.0));
+        FeatureScope {
+            previous,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+/// A guard attributing the allocations of the current thread to a
+/// feature until it goes out of scope.
+pub struct FeatureScope {
+    previous: usize,
+    /// The scope belongs to the thread it was entered on.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for FeatureScope {
+    fn drop(&mut self) {
+        ACTIVE_FEATURE.with(|active| active.set(self.previous));
+    }
+}
+
+/// The bytes allocated and freed for a feature since the allocator was
+/// installed.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct AllocationStats {
+    pub allocated: u64,
+    pub freed: u64,
+}
+
+impl AllocationStats {
+    /// The bytes the feature currently holds.
+    pub fn current(&self) -> u64 {
+        self.allocated.saturating_sub(self.freed)
+    }
+}
+
+/// The counters of a feature on one thread.
+struct FeatureCounters {
+    allocated: AtomicU64,
+    freed: AtomicU64,
+    /// The most bytes held at once, allocated minus freed on this
+    /// thread, since the last report. Memory freed on another thread
+    /// than the one allocating it makes this negative.
+    peak: AtomicI64,
+}
+
+impl FeatureCounters {
+    const fn new() -> Self {
+        Self {
+            allocated: AtomicU64::new(0),
+            freed: AtomicU64::new(0),
+            peak: AtomicI64::new(0),
+        }
+    }
+
+    /// The bytes allocated minus the bytes freed on this thread.
+    fn held(&self) -> i64 {
+        let allocated = self.allocated.load(Ordering::Relaxed);
+        allocated.wrapping_sub(self.freed.load(Ordering::Relaxed)) as i64
+    }
+
+    /// Counts an allocation. Only the shared counters are written by
+    /// several threads and need an atomic addition.
+    fn allocated(&self, bytes: usize, shared: bool) {
+        let allocated = add(&self.allocated, bytes as u64, shared);
+        let held = allocated.wrapping_sub(self.freed.load(Ordering::Relaxed)) as i64;
+        // The peak is only written by the reporter otherwise, so this
+        // rarely has to be an atomic operation.
+        if held > self.peak.load(Ordering::Relaxed) {
+            self.peak.fetch_max(held, Ordering::Relaxed);
+        }
+    }
+
+    fn freed(&self, bytes: usize, shared: bool) {
+        add(&self.freed, bytes as u64, shared);
+    }
+}
+
+/// Adds to a counter and returns its new value.
+fn add(counter: &AtomicU64, value: u64, shared: bool) -> u64 {
+    if shared {
+        return counter
+            .fetch_add(value, Ordering::Relaxed)
+            .wrapping_add(value);
+    }
+    let total = counter.load(Ordering::Relaxed).wrapping_add(value);
+    counter.store(total, Ordering::Relaxed);
+    total
+}
+
+/// The counters of every feature on one thread. Every thread gets its
+/// own cache lines, so threads allocating for the same feature do not
+/// slow each other down.
+#[repr(align(64))]
+struct ThreadCounters {
+    features: [FeatureCounters; MAX_FEATURES],
+}
+
+impl ThreadCounters {
+    const fn new() -> Self {
+        Self {
+            features: [const { FeatureCounters::new() }; MAX_FEATURES],
+        }
+    }
+}
+
+/// A `GlobalAlloc` wrapping another allocator and counting the bytes
+/// allocated and freed under each feature scope.
+///
+/// Counting only takes a thread-local read and a few relaxed atomic
+/// loads and stores per allocation, on counters owned by the
+/// allocating thread. The first `THREAD_COUNTERS` threads allocating
+/// get counters of their own, the following ones share a set of
+/// counters, updated with atomic additions. Allocations made outside
+/// of any feature scope are not counted.
+///
+/// By default memory is attributed to the feature active when it is
+/// freed, which is wrong for memory freed under a different feature
+/// than the one that allocated it. `with_headers` stores the feature
+/// in a small header in front of every allocation instead, so frees
+/// are attributed correctly at the cost of some memory.
+///
+/// ```
+/// use sentry_usage_accountant::AccountingAllocator;
+/// use std::alloc::System;
+///
+/// #[global_allocator]
+/// static ALLOCATOR: AccountingAllocator<System> = AccountingAllocator::with_headers(System);
+/// ```
+pub struct AccountingAllocator<A> {
+    inner: A,
+    headers: bool,
+    /// The id the next feature registered gets.
+    next_feature: AtomicUsize,
+    /// The counters of each thread, followed by the shared ones.
+    threads: [ThreadCounters; THREAD_COUNTERS + 1],
+}
+
+impl<A> AccountingAllocator<A> {
+    /// Wraps `inner`, attributing frees to the active feature.
+    pub const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            headers: false,
+            next_feature: AtomicUsize::new(0),
+            threads: [const { ThreadCounters::new() }; THREAD_COUNTERS + 1],
+        }
+    }
+
+    /// Wraps `inner`, attributing frees to the feature that allocated
+    /// the memory.
+    pub const fn with_headers(inner: A) -> Self {
+        Self {
+            inner,
+            headers: true,
+            next_feature: AtomicUsize::new(0),
+            threads: [const { ThreadCounters::new() }; THREAD_COUNTERS + 1],
+        }
+    }
+
+    /// Registers a new feature. Returns `None` once `MAX_FEATURES`
+    /// features are registered.
+    pub fn register(&self) -> Option<FeatureId> {
+        self.next_feature
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+                (next < MAX_FEATURES).then_some(next + 1)
+            })
+            .ok()
+            .map(FeatureId)
+    }
+
+    /// Returns the bytes allocated and freed for a feature so far, on
+    /// all the threads.
+    pub fn stats(&self, feature: FeatureId) -> AllocationStats {
+        self.threads
+            .iter()
+            .map(|thread| &thread.features[feature.0])
+            .fold(AllocationStats::default(), |stats, counters| {
+                AllocationStats {
+                    allocated: stats.allocated + counters.allocated.load(Ordering::Relaxed),
+                    freed: stats.freed + counters.freed.load(Ordering::Relaxed),
+                }
+            })
+    }
+
+    /// Returns the most bytes a feature held at once since the last
+    /// call, and starts tracking the peak again from the bytes it
+    /// currently holds.
+    ///
+    /// The peaks of the threads are added up, so this is exact for
+    /// memory allocated and freed on the same thread, and an upper
+    /// bound otherwise.
+    pub fn take_peak(&self, feature: FeatureId) -> u64 {
+        let peak: i64 = self
+            .threads
+            .iter()
+            .map(|thread| {
+                let counters = &thread.features[feature.0];
+                let held = counters.held();
+                counters.peak.swap(held, Ordering::Relaxed).max(held)
+            })
+            .sum();
+        peak.max(0) as u64
+    }
+
+    /// Returns the counters of `feature` on the current thread, and
+    /// whether they are shared with other threads.
+    fn counters(&self, feature: usize) -> Option<(&FeatureCounters, bool)> {
+        if feature >= MAX_FEATURES {
+            return None;
+        }
+        let thread = thread_index();
+        Some((
+            &self.threads[thread].features[feature],
+            thread == THREAD_COUNTERS,
+        ))
+    }
+}
+
+/// Returns the index of the counters of the current thread, or
+/// `THREAD_COUNTERS` once every thread counter is taken and while the
+/// thread is being torn down.
+fn thread_index() -> usize {
+    THREAD_INDEX
+        .try_with(|index| {
+            if index.get() == NO_THREAD_INDEX {
+                let next = NEXT_THREAD_INDEX.fetch_add(1, Ordering::Relaxed);
+                index.set(next.min(THREAD_COUNTERS));
+            }
+            index.get()
+        })
+        .unwrap_or(THREAD_COUNTERS)
+}
+
+/// Returns the feature active on the current thread. Nothing is active
+/// while the thread is being torn down.
+fn active_feature() -> usize {
+    ACTIVE_FEATURE
+        .try_with(|active| active.get())
+        .unwrap_or(NO_FEATURE)
+}
+
+/// Returns the layout of an allocation with a header in front of it,
+/// and the offset of the memory handed out.
+fn with_header(layout: Layout) -> Option<(Layout, usize)> {
+    // The offset keeps the memory aligned and leaves room for the
+    // header right before it.
+    let offset = layout.align().max(mem::size_of::<usize>());
+    let size = layout.size().checked_add(offset)?;
+    let layout =
+        Layout::from_size_align(size, layout.align().max(mem::align_of::<usize>())).ok()?;
+    Some((layout, offset))
+}
+
+// SAFETY: all allocations are made by `inner`, with the layout it is
+// given back when they are freed or reallocated. With headers, that is
+// always the layout extended by `with_header`, and the pointer to the
+// start of the header.
+unsafe impl<A: GlobalAlloc> GlobalAlloc for AccountingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let feature = active_feature();
+        if !self.headers {
+            let ptr = self.inner.alloc(layout);
+            if let (false, Some((counters, shared))) = (ptr.is_null(), self.counters(feature)) {
+                counters.allocated(layout.size(), shared);
+            }
+            return ptr;
+        }
+
+        let Some((outer, offset)) = with_header(layout) else {
+            return std::ptr::null_mut();
+        };
+        let base = self.inner.alloc(outer);
+        if base.is_null() {
+            return base;
+        }
+        let ptr = base.add(offset);
+        (ptr as *mut usize).sub(1).write(feature);
+        if let Some((counters, shared)) = self.counters(feature) {
+            counters.allocated(layout.size(), shared);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if !self.headers {
+            if let Some((counters, shared)) = self.counters(active_feature()) {
+                counters.freed(layout.size(), shared);
+            }
+            return self.inner.dealloc(ptr, layout);
+        }
+
+        // `alloc` succeeded with this layout, so does `with_header`.
+        let (outer, offset) = with_header(layout).unwrap();
+        let feature = (ptr as *mut usize).sub(1).read();
+        if let Some((counters, shared)) = self.counters(feature) {
+            counters.freed(layout.size(), shared);
+        }
+        self.inner.dealloc(ptr.sub(offset), outer)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if !self.headers {
+            let new_ptr = self.inner.realloc(ptr, layout, new_size);
+            if let (false, Some((counters, shared))) =
+                (new_ptr.is_null(), self.counters(active_feature()))
+            {
+                counters.freed(layout.size(), shared);
+                counters.allocated(new_size, shared);
+            }
+            return new_ptr;
+        }
+
+        // The memory stays attributed to the feature that allocated it.
+        let (outer, offset) = with_header(layout).unwrap();
+        // Like `alloc`, refuse sizes the extended layout cannot have.
+        let new_outer = new_size
+            .checked_add(offset)
+            .and_then(|size| Layout::from_size_align(size, outer.align()).ok());
+        let Some(new_outer) = new_outer else {
+            return std::ptr::null_mut();
+        };
+        let feature = (ptr as *mut usize).sub(1).read();
+        let base = self.inner.realloc(ptr.sub(offset), outer, new_outer.size());
+        if base.is_null() {
+            return base;
+        }
+        if let Some((counters, shared)) = self.counters(feature) {
+            counters.freed(layout.size(), shared);
+            counters.allocated(new_size, shared);
+        }
+        base.add(offset)
+    }
+}
+
+/// The usage an `AllocationReporter` records for every feature.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum MemoryUsage {
+    /// The most bytes held at once within each bucket, as `Bytes`
+    /// aggregated with `Aggregation::Max`. Features that held nothing
+    /// since the last report are not recorded.
+    #[default]
+    Peak,
+    /// The bytes held over time, as `BytesSec`. The bytes held are
+    /// sampled at every report.
+    ByteSeconds,
+}
+
+/// Registers the features an `AccountingAllocator` tracks and records
+/// their memory usage into a `UsageAccountant`.
+///
+/// `report` has to be called periodically, for example every few
+/// seconds, from the thread owning the accountant. The more often it is
+/// called, the more accurate the usage is.
+pub struct AllocationReporter<A: 'static> {
+    allocator: &'static AccountingAllocator<A>,
+    resource_id: String,
+    usage: MemoryUsage,
+    /// The features registered through this reporter, with the
+    /// app_feature their memory is recorded under.
+    features: Vec<(FeatureId, String)>,
+}
+
+impl<A> AllocationReporter<A> {
+    /// Creates a reporter recording the memory of the features of
+    /// `allocator` against `resource_id`.
+    pub fn new(
+        allocator: &'static AccountingAllocator<A>,
+        resource_id: &str,
+        usage: MemoryUsage,
+    ) -> Self {
+        Self {
+            allocator,
+            resource_id: resource_id.to_owned(),
+            usage,
+            features: Vec::new(),
+        }
+    }
+
+    /// Registers a feature with the allocator, whose memory this
+    /// reporter records under `app_feature`. Returns `None` once
+    /// `MAX_FEATURES` features are registered with the allocator.
+    pub fn register(&mut self, app_feature: &str) -> Option<FeatureId> {
+        let feature = self.allocator.register()?;
+        self.features.push((feature, app_feature.to_owned()));
+        Some(feature)
+    }
+
+    /// Records the memory usage of every registered feature.
+    pub fn report<P: Producer>(
+        &self,
+        accountant: &mut UsageAccountant<P>,
+    ) -> Result<(), AccountantError<P::Error>> {
+        for &(feature, ref app_feature) in &self.features {
+            match self.usage {
+                MemoryUsage::Peak => {
+                    let peak = self.allocator.take_peak(feature);
+                    if peak == 0 {
+                        continue;
+                    }
+                    accountant.record_aggregated(
+                        &self.resource_id,
+                        app_feature,
+                        peak,
+                        UsageUnit::Bytes,
+                        Aggregation::Max,
+                    )?;
+                }
+                MemoryUsage::ByteSeconds => {
+                    let current = self.allocator.stats(feature).current();
+                    accountant.set_gauge(&self.resource_id, app_feature, current)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::System;
+
+    use chrono::{Duration, TimeZone, Utc};
+
+    use crate::accountant::Message;
+    use crate::{DummyProducer, MockClock};
+
+    use super::*;
+
+    #[test]
+    fn test_attributes_allocations() {
+        for headers in [false, true] {
+            let allocator: &'static AccountingAllocator<System> = Box::leak(Box::new(if headers {
+                AccountingAllocator::with_headers(System)
+            } else {
+                AccountingAllocator::new(System)
+            }));
+            let mut reporter = AllocationReporter::new(allocator, "relay", MemoryUsage::Peak);
+            let transactions = reporter.register("transactions").unwrap();
+            let spans = reporter.register("spans").unwrap();
+            // Holds nothing, so nothing is recorded for it.
+            reporter.register("profiles").unwrap();
+            let layout = Layout::from_size_align(1000, 16).unwrap();
+
+            let (first, second) = {
+                let _transactions = transactions.enter();
+                let first = unsafe { allocator.alloc(layout) };
+                let second = {
+                    let _spans = spans.enter();
+                    unsafe { allocator.alloc(layout) }
+                };
+                (first, second)
+            };
+            assert_eq!(first as usize % 16, 0);
+            // Not counted, no feature is active.
+            let third = unsafe { allocator.alloc(layout) };
+            unsafe { allocator.dealloc(third, layout) };
+
+            let first = {
+                let _transactions = transactions.enter();
+                unsafe { allocator.realloc(first, layout, 3000) }
+            };
+            assert_eq!(allocator.stats(transactions).current(), 3000);
+            {
+                // A spike between reports still counts as the peak.
+                let _spans = spans.enter();
+                let spike = Layout::from_size_align(5000, 16).unwrap();
+                unsafe { allocator.dealloc(allocator.alloc(spike), spike) };
+            }
+            {
+                let _transactions = transactions.enter();
+                unsafe { allocator.dealloc(second, layout) };
+            }
+            // With headers, the free goes to the feature that allocated.
+            let expected = if headers { (3000, 0) } else { (2000, 1000) };
+            assert_eq!(
+                (
+                    allocator.stats(transactions).current(),
+                    allocator.stats(spans).current()
+                ),
+                expected
+            );
+
+            let mut accountant = UsageAccountant::new(DummyProducer::default(), None);
+            reporter.report(&mut accountant).unwrap();
+            accountant.flush().unwrap();
+            let mut peaks: Vec<_> = accountant
+                .producer
+                .messages
+                .iter()
+                .map(|payload| {
+                    let message: Message = serde_json::from_slice(payload).unwrap();
+                    assert_eq!(message.aggregation, Aggregation::Max);
+                    (message.app_feature, message.amount)
+                })
+                .collect();
+            peaks.sort();
+            assert_eq!(
+                peaks,
+                vec![
+                    ("spans".to_string(), 6000),
+                    ("transactions".to_string(), 3000)
+                ]
+            );
+
+            let _transactions = transactions.enter();
+            unsafe { allocator.dealloc(first, Layout::from_size_align(3000, 16).unwrap()) };
+        }
+    }
+
+    #[test]
+    fn test_reporters_share_features() {
+        let allocator: &'static AccountingAllocator<System> =
+            Box::leak(Box::new(AccountingAllocator::new(System)));
+        let mut first = AllocationReporter::new(allocator, "relay", MemoryUsage::Peak);
+        let mut second = AllocationReporter::new(allocator, "relay", MemoryUsage::Peak);
+
+        let transactions = first.register("transactions").unwrap();
+        let spans = second.register("spans").unwrap();
+        assert_ne!(transactions, spans);
+
+        for _ in 2..MAX_FEATURES {
+            first.register("profiles").unwrap();
+        }
+        assert_eq!(second.register("replays"), None);
+    }
+
+    #[test]
+    fn test_sums_threads() {
+        let allocator: &'static AccountingAllocator<System> =
+            Box::leak(Box::new(AccountingAllocator::new(System)));
+        let mut reporter = AllocationReporter::new(allocator, "relay", MemoryUsage::Peak);
+        let spans = reporter.register("spans").unwrap();
+        let layout = Layout::from_size_align(1000, 16).unwrap();
+
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                std::thread::spawn(move || {
+                    let _spans = spans.enter();
+                    unsafe { allocator.dealloc(allocator.alloc(layout), layout) };
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let stats = allocator.stats(spans);
+        assert_eq!((stats.allocated, stats.freed), (2000, 2000));
+        // Each thread held its allocation at some point.
+        assert_eq!(allocator.take_peak(spans), 2000);
+        assert_eq!(allocator.take_peak(spans), 0);
+    }
+
+    #[test]
+    fn test_realloc_rejects_invalid_layout() {
+        let allocator: &'static AccountingAllocator<System> =
+            Box::leak(Box::new(AccountingAllocator::with_headers(System)));
+        let mut reporter = AllocationReporter::new(allocator, "relay", MemoryUsage::Peak);
+        let spans = reporter.register("spans").unwrap();
+        let layout = Layout::from_size_align(1000, 16).unwrap();
+
+        let _spans = spans.enter();
+        let ptr = unsafe { allocator.alloc(layout) };
+        // Fits a `usize` with the header, but not a `Layout`.
+        let new_ptr = unsafe { allocator.realloc(ptr, layout, isize::MAX as usize - 8) };
+        assert!(new_ptr.is_null());
+        assert_eq!(allocator.stats(spans).current(), 1000);
+        unsafe { allocator.dealloc(ptr, layout) };
+    }
+
+    #[test]
+    fn test_byte_seconds() {
+        let allocator: &'static AccountingAllocator<System> =
+            Box::leak(Box::new(AccountingAllocator::new(System)));
+        let mut reporter = AllocationReporter::new(allocator, "relay", MemoryUsage::ByteSeconds);
+        let caches = reporter.register("caches").unwrap();
+        let clock = MockClock::new(Utc.with_ymd_and_hms(2023, 10, 8, 22, 15, 10).unwrap());
+        let mut accountant =
+            UsageAccountant::new(DummyProducer::default(), None).with_clock(clock.clone());
+        let layout = Layout::from_size_align(1000, 16).unwrap();
+
+        let ptr = {
+            let _caches = caches.enter();
+            unsafe { allocator.alloc(layout) }
+        };
+        reporter.report(&mut accountant).unwrap();
+        clock.advance(Duration::seconds(20));
+        reporter.report(&mut accountant).unwrap();
+        {
+            let _caches = caches.enter();
+            unsafe { allocator.dealloc(ptr, layout) };
+        }
+        clock.advance(Duration::seconds(10));
+        reporter.report(&mut accountant).unwrap();
+        accountant.flush().unwrap();
+
+        let messages: Vec<Message> = accountant
+            .producer
+            .messages
+            .iter()
+            .map(|payload| serde_json::from_slice(payload).unwrap())
+            .collect();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].app_feature, "caches");
+        assert_eq!(messages[0].usage_unit, UsageUnit::BytesSec);
+        assert_eq!(messages[0].amount, 30_000);
+    }
+}
@@ -0,0 +1,661 @@
+//! This module contains the accountant flavor meant for tokio based
+//! services.
+//!
+//! Recording usage only touches the in-memory accumulator, so it never
+//! blocks the executor. A background task owns the producer and
+//! flushes the accumulator whenever its flush policy says so.
+//!
+
+use chrono::{DateTime, Duration, Utc};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::mem;
+use std::panic;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{oneshot, Notify};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{event, Level};
+
+use crate::accountant::{normalize, tag_set, BatchOutcome};
+use crate::accumulator::{quantize, UsageAccumulator};
+use crate::lock::lock;
+use crate::{
+    AccountantError, Aggregation, BatchSizeLimits, CardinalityLimits, Clock, FlushPolicy,
+    FlushReport, OverflowPolicy, RetryPolicy, SystemClock, TimestampPolicy, UnitRegistry,
+    UsageSnapshot, UsageUnit, DEFAULT_MAX_TAGS,
+};
+
+/// The asynchronous counterpart of the `Producer` trait.
+///
+/// The producer is moved into the flush task, so it has to be `Send`
+/// and so does the future returned by `send`.
+pub trait AsyncProducer: Send + 'static {
+    type Error: Send + 'static;
+
+    fn send(&mut self, payload: Vec<u8>) -> impl Future<Output = Result<(), Self::Error>> + Send;
+}
+
+/// An accountant for tokio based services.
+///
+/// `record` only stores data in the accumulator, while a task spawned
+/// on the current tokio runtime flushes it through the `AsyncProducer`
+/// once the flush policy says so. The task checks the policy when
+/// usage is recorded, at the end of every bucket, and every second
+/// while usage is pending. Errors produced by periodic flushes are
+/// logged, the one produced by the final flush is returned by
+/// `shutdown`.
+///
+/// Call `shutdown` before the application exits to make sure the last
+/// batch is produced. Dropping the accountant without shutting it down
+/// asks the task to flush, but nothing waits for it to complete.
+pub struct AsyncUsageAccountant<P: AsyncProducer> {
+    state: Arc<Mutex<State>>,
+    shutdown: Option<oneshot::Sender<()>>,
+    /// Wakes the flush task up when the batch is ready early.
+    flush_now: Arc<Notify>,
+    task: Option<JoinHandle<Result<FlushReport, AccountantError<P::Error>>>>,
+    timestamp_policy: TimestampPolicy,
+    max_tags: usize,
+    units: Option<UnitRegistry>,
+    clock: Arc<dyn Clock>,
+}
+
+#[cfg(feature = "kafka")]
+impl AsyncUsageAccountant<crate::KafkaProducer> {
+    /// Instantiates an AsyncUsageAccountant from a Kafka config object.
+    ///
+    /// Like `new`, this has to be called from within a tokio runtime.
+    pub fn new_with_kafka(
+        producer_config: crate::KafkaConfig,
+        granularity: Option<Duration>,
+    ) -> AsyncUsageAccountant<crate::KafkaProducer> {
+        AsyncUsageAccountant::new(crate::KafkaProducer::new(producer_config), granularity)
+    }
+}
+
+impl<P: AsyncProducer> AsyncUsageAccountant<P> {
+    /// Instantiates an AsyncUsageAccountant and spawns its flush task.
+    ///
+    /// This has to be called from within a tokio runtime.
+    pub fn new(producer: P, granularity: Option<Duration>) -> Self {
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        let state = Arc::new(Mutex::new(State {
+            accumulator: UsageAccumulator::new(granularity),
+            retry_policy: RetryPolicy::default(),
+            clock: clock.clone(),
+        }));
+        let (shutdown, shutdown_rx) = oneshot::channel();
+        let flush_now = Arc::new(Notify::new());
+
+        let task = tokio::spawn(flush_task(
+            producer,
+            state.clone(),
+            flush_now.clone(),
+            shutdown_rx,
+        ));
+
+        AsyncUsageAccountant {
+            state,
+            shutdown: Some(shutdown),
+            flush_now,
+            task: Some(task),
+            timestamp_policy: TimestampPolicy::default(),
+            max_tags: DEFAULT_MAX_TAGS,
+            units: None,
+            clock,
+        }
+    }
+
+    /// Sets the clock the current time is read from, the system clock
+    /// by default. Meant for tests, with a `MockClock`.
+    ///
+    /// The flush task sleeps in real time until the end of the bucket
+    /// according to `clock`.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        lock(&self.state).clock = self.clock.clone();
+        self
+    }
+
+    /// Sets how the amounts recorded with `unit` are combined within a
+    /// bucket. Units are summed unless configured otherwise.
+    pub fn with_aggregation(self, unit: UsageUnit, aggregation: Aggregation) -> Self {
+        lock(&self.state)
+            .accumulator
+            .set_aggregation(unit, aggregation);
+        self
+    }
+
+    /// Makes the accountant convert usage to the canonical unit of its
+    /// dimension before accumulating it, so usage recorded in
+    /// compatible units is aggregated together. Custom units are
+    /// converted according to `units`.
+    ///
+    /// Recording fails with `UnitOverflow` if the converted amount does
+    /// not fit in a `u64`.
+    pub fn with_unit_normalization(mut self, units: UnitRegistry) -> Self {
+        self.units = Some(units);
+        self
+    }
+
+    /// Sets what happens when the amount accumulated for a key does
+    /// not fit in a `u64`. Amounts saturate by default.
+    pub fn with_overflow_policy(self, overflow_policy: OverflowPolicy) -> Self {
+        lock(&self.state)
+            .accumulator
+            .set_overflow_policy(overflow_policy);
+        self
+    }
+
+    /// Returns how many times the amount accumulated for a key
+    /// overflowed since the accountant was created.
+    pub fn overflows(&self) -> u64 {
+        lock(&self.state).accumulator.overflows()
+    }
+
+    /// Returns a copy of the usage accumulated and not flushed yet,
+    /// without flushing it.
+    pub fn snapshot(&self) -> UsageSnapshot {
+        lock(&self.state).accumulator.snapshot()
+    }
+
+    /// Caps the number of distinct keys in a batch, so a dynamic value
+    /// passed as app_feature or tag cannot flood the topic.
+    pub fn with_cardinality_limits(self, cardinality_limits: CardinalityLimits) -> Self {
+        lock(&self.state)
+            .accumulator
+            .set_cardinality_limits(cardinality_limits);
+        self
+    }
+
+    /// Returns how many records hit a cardinality limit since the
+    /// accountant was created.
+    pub fn limited_records(&self) -> u64 {
+        lock(&self.state).accumulator.limited_records()
+    }
+
+    /// Bounds the size of the batch, which is flushed as soon as it
+    /// reaches one of the limits instead of waiting for the
+    /// granularity to elapse.
+    ///
+    /// The limits are checked along with the flush policy, whichever
+    /// of the two is set first.
+    pub fn with_batch_size_limits(self, size_limits: BatchSizeLimits) -> Self {
+        lock(&self.state).accumulator.set_size_limits(size_limits);
+        self
+    }
+
+    /// Sets the policy deciding when the batch is flushed. By default
+    /// it is flushed once the bucket it was opened in is over.
+    ///
+    /// The batch size limits still apply, a batch reaching one of them
+    /// is flushed whatever the policy says.
+    pub fn with_flush_policy(self, flush_policy: impl FlushPolicy + 'static) -> Self {
+        lock(&self.state)
+            .accumulator
+            .set_flush_policy(Arc::new(flush_policy));
+        self
+    }
+
+    /// Sets the maximum number of tags `record_with_tags` accepts.
+    pub fn with_max_tags(mut self, max_tags: usize) -> Self {
+        self.max_tags = max_tags;
+        self
+    }
+
+    /// Sets the policy applied to the timestamps passed to `record_at`.
+    pub fn with_timestamp_policy(mut self, timestamp_policy: TimestampPolicy) -> Self {
+        self.timestamp_policy = timestamp_policy;
+        self
+    }
+
+    /// Sets the policy deciding how long usage that could not be
+    /// produced is kept for later flushes.
+    pub fn with_retry_policy(self, retry_policy: RetryPolicy) -> Self {
+        lock(&self.state).retry_policy = retry_policy;
+        self
+    }
+
+    /// Records an amount of usage for a resource, and app_feature. See
+    /// `UsageAccountant::record`.
+    ///
+    /// This never produces, the batch is flushed by the background
+    /// task. It only fails if the amount cannot be normalized.
+    pub fn record(
+        &self,
+        resource_id: &str,
+        app_feature: &str,
+        amount: u64,
+        unit: UsageUnit,
+    ) -> Result<(), AccountantError<P::Error>> {
+        let (amount, unit) = normalize(self.units.as_ref(), amount, unit)?;
+        let current_time = self.clock.now();
+        self.record_received(
+            current_time,
+            current_time,
+            resource_id,
+            app_feature,
+            amount,
+            unit,
+        );
+        Ok(())
+    }
+
+    /// Records an amount of usage for a resource, and app_feature
+    /// that happened at `timestamp`. See `UsageAccountant::record_at`,
+    /// it never produces, like `record`.
+    pub fn record_at(
+        &self,
+        timestamp: DateTime<Utc>,
+        resource_id: &str,
+        app_feature: &str,
+        amount: u64,
+        unit: UsageUnit,
+    ) -> Result<(), AccountantError<P::Error>> {
+        let (amount, unit) = normalize(self.units.as_ref(), amount, unit)?;
+        let current_time = self.clock.now();
+        if let Some(usage_time) = self.timestamp_policy.apply(timestamp, current_time) {
+            self.record_received(
+                current_time,
+                usage_time,
+                resource_id,
+                app_feature,
+                amount,
+                unit,
+            );
+        }
+        Ok(())
+    }
+
+    /// Records normalized usage that happened at `usage_time` but is
+    /// only being recorded at `received_time`.
+    fn record_received(
+        &self,
+        received_time: DateTime<Utc>,
+        usage_time: DateTime<Utc>,
+        resource_id: &str,
+        app_feature: &str,
+        amount: u64,
+        unit: UsageUnit,
+    ) {
+        let mut state = lock(&self.state);
+        state.accumulator.record_received(
+            received_time,
+            usage_time,
+            resource_id,
+            app_feature,
+            amount,
+            unit,
+        );
+        self.notify_if_ready(&state, received_time);
+    }
+
+    /// Sets the number of bytes a resource, and app_feature currently
+    /// holds. See `UsageAccountant::set_gauge`.
+    ///
+    /// This never produces, like `record`, and never fails. It returns
+    /// a `Result` like the other accountants.
+    pub fn set_gauge(
+        &self,
+        resource_id: &str,
+        app_feature: &str,
+        bytes: u64,
+    ) -> Result<(), AccountantError<P::Error>> {
+        let current_time = self.clock.now();
+        let mut state = lock(&self.state);
+        state.accumulator.set_gauge(
+            current_time,
+            resource_id,
+            app_feature,
+            BTreeMap::new(),
+            bytes,
+        );
+        self.notify_if_ready(&state, current_time);
+        Ok(())
+    }
+
+    /// Records an amount of usage for a resource, and app_feature
+    /// broken down by additional dimensions. See
+    /// `UsageAccountant::record_with_tags`, it never produces, like
+    /// `record`.
+    pub fn record_with_tags(
+        &self,
+        resource_id: &str,
+        app_feature: &str,
+        amount: u64,
+        unit: UsageUnit,
+        tags: &[(&str, &str)],
+    ) -> Result<(), AccountantError<P::Error>> {
+        let tags = tag_set(tags, self.max_tags)?;
+        let (amount, unit) = normalize(self.units.as_ref(), amount, unit)?;
+        let current_time = self.clock.now();
+        let mut state = lock(&self.state);
+        let mut key = state
+            .accumulator
+            .key(current_time, resource_id, app_feature, unit);
+        key.tags = tags;
+        state.accumulator.add(current_time, key, amount);
+        self.notify_if_ready(&state, current_time);
+        Ok(())
+    }
+
+    /// Makes the flush task flush right away if the batch is ready to
+    /// be flushed before the next tick.
+    fn notify_if_ready(&self, state: &State, current_time: DateTime<Utc>) {
+        if state.accumulator.should_flush(current_time) {
+            self.flush_now.notify_one();
+        }
+    }
+
+    /// Stops the flush task and waits for it to produce the last batch.
+    /// Returns the report of that last flush. Usage the last flush
+    /// fails to produce is lost, there is no later flush to retry it.
+    pub async fn shutdown(mut self) -> Result<FlushReport, AccountantError<P::Error>> {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        match self.task.take() {
+            Some(task) => match task.await {
+                Ok(result) => result,
+                Err(error) if error.is_panic() => panic::resume_unwind(error.into_panic()),
+                // The runtime is shutting down, there is nothing left
+                // we can do.
+                Err(_) => Ok(FlushReport::default()),
+            },
+            None => Ok(FlushReport::default()),
+        }
+    }
+}
+
+impl<P: AsyncProducer> Drop for AsyncUsageAccountant<P> {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+async fn flush_task<P: AsyncProducer>(
+    mut producer: P,
+    state: Arc<Mutex<State>>,
+    flush_now: Arc<Notify>,
+    mut shutdown: oneshot::Receiver<()>,
+) -> Result<FlushReport, AccountantError<P::Error>> {
+    loop {
+        let next_check = until_next_check(&lock(&state));
+        tokio::select! {
+            _ = time::sleep(next_check) => {}
+            _ = flush_now.notified() => {}
+            // Both an explicit shutdown and the accountant being
+            // dropped end up here.
+            _ = &mut shutdown => {
+                return send_batch(&mut producer, &state).await.into_result(0);
+            }
+        }
+        let ready = {
+            let state = lock(&state);
+            state.accumulator.should_flush(state.clock.now())
+        };
+        if !ready {
+            continue;
+        }
+        match produce_batch(&mut producer, &state).await {
+            Ok(report) => event!(Level::DEBUG, "Produced {} usage messages.", report.messages),
+            Err(error) => event!(Level::ERROR, "Failed to produce usage batch. {}", error),
+        }
+    }
+}
+
+/// Returns how long until the flush task checks the flush policy
+/// again: at the end of the bucket the current time falls in, which is
+/// when the default policy flushes, or within a second while usage is
+/// pending for the policies flushing at other times.
+fn until_next_check(state: &State) -> std::time::Duration {
+    let current_time = state.clock.now();
+    let granularity = state.accumulator.granularity();
+    let bucket_end = quantize(current_time, granularity) + granularity;
+    let mut next_check = (bucket_end - current_time).to_std().unwrap_or_default();
+    if state.accumulator.opened_at().is_some() {
+        next_check = next_check.min(std::time::Duration::from_secs(1));
+    }
+    // There is no point in checking more often than once per
+    // millisecond, which also covers a zero granularity.
+    next_check.max(std::time::Duration::from_millis(1))
+}
+
+/// The state shared by the accountant and its flush task.
+struct State {
+    accumulator: UsageAccumulator,
+    retry_policy: RetryPolicy,
+    clock: Arc<dyn Clock>,
+}
+
+/// Flushes the accumulator and sends the batch through the producer.
+async fn send_batch<P: AsyncProducer>(
+    producer: &mut P,
+    state: &Mutex<State>,
+) -> BatchOutcome<P::Error> {
+    let batch = {
+        let mut state = lock(state);
+        let current_time = state.clock.now();
+        state.accumulator.flush_at(current_time)
+    };
+    let mut outcome = BatchOutcome::new(batch);
+    while let Some(payload) = outcome.next_payload() {
+        let bytes = payload.len();
+        match producer.send(payload).await {
+            Ok(()) => outcome.sent(bytes),
+            Err(error) => outcome.failed(error),
+        }
+    }
+    outcome
+}
+
+/// Flushes the accumulator, and puts the usage that could not be sent
+/// back in it according to the retry policy.
+async fn produce_batch<P: AsyncProducer>(
+    producer: &mut P,
+    state: &Mutex<State>,
+) -> Result<FlushReport, AccountantError<P::Error>> {
+    let mut outcome = send_batch(producer, state).await;
+    let retained = {
+        let state = &mut *lock(state);
+        state.accumulator.retain(
+            mem::take(&mut outcome.unsent),
+            &state.retry_policy,
+            state.clock.now(),
+        )
+    };
+    outcome.into_result(retained)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use crate::accountant::Message;
+    use crate::{MaxAge, MockClock};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct SharedDummyProducer {
+        messages: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl AsyncProducer for SharedDummyProducer {
+        type Error = std::convert::Infallible;
+
+        async fn send(&mut self, payload: Vec<u8>) -> Result<(), Self::Error> {
+            self.messages.lock().unwrap().push(payload);
+            Ok(())
+        }
+    }
+
+    struct FailingProducer;
+
+    impl AsyncProducer for FailingProducer {
+        type Error = ();
+
+        async fn send(&mut self, _payload: Vec<u8>) -> Result<(), Self::Error> {
+            Err(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flushes_at_bucket_end() {
+        let producer = SharedDummyProducer::default();
+        let clock = MockClock::new(Utc.with_ymd_and_hms(2023, 10, 8, 22, 15, 10).unwrap());
+        let accountant =
+            AsyncUsageAccountant::new(producer.clone(), None).with_clock(clock.clone());
+
+        accountant
+            .record("resource_1", "transactions", 100, UsageUnit::Bytes)
+            .unwrap();
+        accountant
+            .record("resource_1", "transactions", 100, UsageUnit::Bytes)
+            .unwrap();
+        clock.advance(Duration::seconds(49));
+        time::sleep(std::time::Duration::from_secs(49)).await;
+        assert!(producer.messages.lock().unwrap().is_empty());
+
+        // The bucket ends 50 seconds after the clock.
+        clock.advance(Duration::seconds(2));
+        time::sleep(std::time::Duration::from_secs(2)).await;
+        {
+            let messages = producer.messages.lock().unwrap();
+            assert_eq!(messages.len(), 1);
+            let message: Message = serde_json::from_slice(&messages[0]).unwrap();
+            assert_eq!(message.app_feature, "transactions");
+            assert_eq!(message.amount, 200);
+        }
+
+        accountant.shutdown().await.unwrap();
+        assert_eq!(producer.messages.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_consults_flush_policy() {
+        let producer = SharedDummyProducer::default();
+        let clock = MockClock::new(Utc.with_ymd_and_hms(2023, 10, 8, 22, 15, 10).unwrap());
+        let accountant = AsyncUsageAccountant::new(producer.clone(), None)
+            .with_clock(clock.clone())
+            .with_flush_policy(MaxAge(Duration::seconds(90)));
+
+        accountant
+            .record("resource_1", "transactions", 100, UsageUnit::Bytes)
+            .unwrap();
+        // Past the end of the bucket, but not old enough.
+        clock.advance(Duration::seconds(60));
+        time::sleep(std::time::Duration::from_secs(60)).await;
+        assert!(producer.messages.lock().unwrap().is_empty());
+
+        clock.advance(Duration::seconds(30));
+        time::sleep(std::time::Duration::from_secs(1)).await;
+        assert_eq!(producer.messages.lock().unwrap().len(), 1);
+
+        accountant.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flushes_full_batch_early() {
+        let producer = SharedDummyProducer::default();
+        let accountant = AsyncUsageAccountant::new(producer.clone(), None).with_batch_size_limits(
+            BatchSizeLimits {
+                max_keys: 2,
+                ..BatchSizeLimits::default()
+            },
+        );
+
+        accountant
+            .record("resource_1", "transactions", 100, UsageUnit::Bytes)
+            .unwrap();
+        tokio::task::yield_now().await;
+        assert!(producer.messages.lock().unwrap().is_empty());
+
+        accountant
+            .record("resource_1", "spans", 100, UsageUnit::Bytes)
+            .unwrap();
+        tokio::task::yield_now().await;
+        assert_eq!(producer.messages.lock().unwrap().len(), 2);
+
+        accountant.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_last_batch() {
+        let producer = SharedDummyProducer::default();
+        let accountant = AsyncUsageAccountant::new(producer.clone(), None);
+
+        accountant
+            .record("resource_1", "transactions", 100, UsageUnit::Bytes)
+            .unwrap();
+        accountant
+            .record("resource_1", "spans", 200, UsageUnit::Bytes)
+            .unwrap();
+        accountant.shutdown().await.unwrap();
+
+        assert_eq!(producer.messages.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_loses_unsent_usage() {
+        let accountant = AsyncUsageAccountant::new(FailingProducer, None);
+
+        accountant
+            .record("resource_1", "transactions", 100, UsageUnit::Bytes)
+            .unwrap();
+        accountant
+            .record("resource_1", "spans", 200, UsageUnit::Bytes)
+            .unwrap();
+
+        assert!(matches!(
+            accountant.shutdown().await,
+            Err(AccountantError::PartialFlush {
+                sent: 0,
+                retained: 0,
+                lost: 2,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_set_gauge_flushes_full_batch_early() {
+        let producer = SharedDummyProducer::default();
+        let clock = MockClock::new(Utc.with_ymd_and_hms(2023, 10, 8, 22, 15, 10).unwrap());
+        let accountant = AsyncUsageAccountant::new(producer.clone(), None)
+            .with_clock(clock.clone())
+            .with_batch_size_limits(BatchSizeLimits {
+                max_keys: 2,
+                ..BatchSizeLimits::default()
+            });
+
+        accountant
+            .record("resource_1", "transactions", 100, UsageUnit::Bytes)
+            .unwrap();
+        accountant.set_gauge("resource_1", "storage", 1000).unwrap();
+        clock.advance(Duration::seconds(10));
+        // Closing the gauge adds its byte-seconds to the batch.
+        accountant.set_gauge("resource_1", "storage", 0).unwrap();
+        tokio::task::yield_now().await;
+        assert_eq!(producer.messages.lock().unwrap().len(), 2);
+
+        accountant.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_record_reports_overflow() {
+        let producer = SharedDummyProducer::default();
+        let accountant = AsyncUsageAccountant::new(producer.clone(), None)
+            .with_unit_normalization(UnitRegistry::new());
+
+        assert!(matches!(
+            accountant.record("resource_1", "transactions", u64::MAX, UsageUnit::Seconds),
+            Err(AccountantError::UnitOverflow { .. })
+        ));
+        accountant.shutdown().await.unwrap();
+        assert!(producer.messages.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,50 @@
+//! This module contains the limits on the size of a batch that make
+//! the accountants flush before the batch is due.
+//!
+
+use crate::{AnyOf, BatchState, BucketBoundary, MaxBytes, MaxKeys};
+
+/// Bounds the memory a batch takes between two flushes.
+///
+/// Once the batch holds `max_keys` keys or an estimated `max_bytes`
+/// bytes, it is flushed right away instead of waiting for the
+/// granularity to elapse. Buckets are not affected, a bucket flushed
+/// early just ends up in several messages with the same key, which
+/// consumers combine according to the aggregation of the message. The
+/// default limits are unbounded.
+///
+/// The accountants check the limits along with their flush policy, so
+/// setting one does not replace the other.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BatchSizeLimits {
+    pub max_keys: usize,
+    pub max_bytes: usize,
+}
+
+impl Default for BatchSizeLimits {
+    fn default() -> Self {
+        Self {
+            max_keys: usize::MAX,
+            max_bytes: usize::MAX,
+        }
+    }
+}
+
+impl BatchSizeLimits {
+    /// Returns true if the batch reached one of the limits.
+    pub(crate) fn reached(&self, batch: &BatchState) -> bool {
+        batch.keys >= self.max_keys || batch.estimated_bytes >= self.max_bytes
+    }
+
+    /// The policy flushing once the bucket is over or the batch reaches
+    /// one of the limits: `AnyOf(BucketBoundary, MaxKeys, MaxBytes)`.
+    /// This is what an accountant with these limits and the default
+    /// flush policy does.
+    pub fn flush_policy(self) -> AnyOf {
+        AnyOf(vec![
+            Box::new(BucketBoundary),
+            Box::new(MaxKeys(self.max_keys)),
+            Box::new(MaxBytes(self.max_bytes)),
+        ])
+    }
+}
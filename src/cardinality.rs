@@ -0,0 +1,96 @@
+//! This module contains the limits protecting the accumulator from an
+//! unbounded number of keys.
+//!
+//! A bug putting a dynamic value in `app_feature` or in a tag would
+//! otherwise grow the batch without bound and flood the topic.
+//!
+
+use chrono::Duration;
+use std::collections::HashMap;
+
+/// The feature usage over the cardinality limits is recorded under by
+/// default.
+pub const OTHER_FEATURE: &str = "__other__";
+
+/// What to do with usage that would create a key over the limits of a
+/// `CardinalityLimits`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum OverLimitAction {
+    /// Record the usage under this app_feature instead, without tags.
+    Other(String),
+    /// Discard the usage.
+    Drop,
+}
+
+/// Caps the number of distinct keys in a batch, overall and per
+/// resource.
+///
+/// Usage for keys already in the batch is always recorded. Every
+/// record hitting a limit is counted, and a warning naming the
+/// resource is logged at most once per `warning_interval`. The default
+/// limits are unbounded.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CardinalityLimits {
+    pub max_keys: usize,
+    pub max_keys_per_resource: usize,
+    pub action: OverLimitAction,
+    pub warning_interval: Duration,
+}
+
+impl Default for CardinalityLimits {
+    fn default() -> Self {
+        Self {
+            max_keys: usize::MAX,
+            max_keys_per_resource: usize::MAX,
+            action: OverLimitAction::Other(OTHER_FEATURE.to_string()),
+            warning_interval: Duration::minutes(1),
+        }
+    }
+}
+
+/// Counts the keys admitted by an accumulator. Several accumulators
+/// can share one, so the `CardinalityLimits` hold across all the
+/// shards of a `SharedUsageAccountant` rather than for each of them.
+#[derive(Debug, Default)]
+pub(crate) struct KeyBudget {
+    keys: usize,
+    resource_keys: HashMap<String, usize>,
+}
+
+impl KeyBudget {
+    /// Counts a key of `resource_id` if it is within `limits`. Returns
+    /// false, without counting it, otherwise.
+    ///
+    /// Checking and counting happen together, so accumulators sharing
+    /// the budget cannot both take its last key.
+    pub(crate) fn try_admit(&mut self, limits: &CardinalityLimits, resource_id: &str) -> bool {
+        let resource_keys = self.resource_keys.get(resource_id).copied().unwrap_or(0);
+        if self.keys >= limits.max_keys || resource_keys >= limits.max_keys_per_resource {
+            return false;
+        }
+        self.add(resource_id);
+        true
+    }
+
+    /// Counts a key of `resource_id`, regardless of the limits.
+    pub(crate) fn add(&mut self, resource_id: &str) {
+        self.keys += 1;
+        *self
+            .resource_keys
+            .entry(resource_id.to_owned())
+            .or_default() += 1;
+    }
+
+    /// Stops counting the keys an accumulator flushed.
+    pub(crate) fn release(&mut self, resource_keys: &HashMap<String, usize>) {
+        for (resource_id, count) in resource_keys {
+            self.keys = self.keys.saturating_sub(*count);
+            if let Some(keys) = self.resource_keys.get_mut(resource_id) {
+                *keys = keys.saturating_sub(*count);
+                if *keys == 0 {
+                    self.resource_keys.remove(resource_id);
+                }
+            }
+        }
+    }
+}
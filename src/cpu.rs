@@ -0,0 +1,44 @@
+//! This module reads the CPU time consumed by the current thread.
+//!
+//! Unlike wall time, thread CPU time does not advance while the
+//! thread is blocked, so it attributes CPU usage correctly to the
+//! code running on the thread.
+//!
+
+use std::time::Duration;
+
+/// Returns the CPU time consumed by the calling thread so far, or
+/// `None` if the platform does not support it.
+#[cfg(unix)]
+pub(crate) fn thread_cpu_time() -> Option<Duration> {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `time` is a valid timespec for clock_gettime to write to.
+    let ret = unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut time) };
+    if ret != 0 {
+        return None;
+    }
+    Some(Duration::new(time.tv_sec as u64, time.tv_nsec as u32))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn thread_cpu_time() -> Option<Duration> {
+    None
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_thread_cpu_time_advances() {
+        let start = thread_cpu_time().unwrap();
+        let mut value: u64 = 0;
+        for i in 0..1_000_000u64 {
+            value = std::hint::black_box(value.wrapping_add(i));
+        }
+        assert!(thread_cpu_time().unwrap() > start);
+    }
+}
@@ -0,0 +1,194 @@
+//! This module contains a collector attributing the CPU time of a
+//! thread to the features it runs.
+//!
+//! Timing each feature by hand measures wall time, which keeps running
+//! while the thread is blocked. The collector reads the CPU time of the
+//! thread whenever it enters or leaves a feature scope instead, and
+//! records what was consumed in between as `CpuNanoseconds`.
+//!
+
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::time::Duration;
+use tracing::{event, Level};
+
+use crate::cpu::thread_cpu_time;
+use crate::{LocalUsageAccountant, Producer, UsageUnit};
+
+/// Attributes the CPU time of the current thread to the innermost
+/// feature scope it is in, against a single resource.
+///
+/// Scopes are entered with `enter`, which returns a guard leaving the
+/// scope when it goes out of scope. Scopes nest: the CPU time consumed
+/// in an inner scope is only attributed to the inner feature. CPU time
+/// consumed outside of any scope is not recorded.
+///
+/// The collector reads the CPU time of the thread it runs on, so it
+/// cannot be sent to another thread. Nothing is recorded on platforms
+/// where thread CPU time is not available.
+///
+/// ```
+/// use sentry_usage_accountant::{CpuCollector, LocalUsageAccountant, Producer, SharedUsageAccountant};
+/// use std::sync::Arc;
+///
+/// fn process<P: Producer>(central: Arc<SharedUsageAccountant<P>>) {
+///     let mut collector = CpuCollector::new(LocalUsageAccountant::new(central), "relay");
+///     let mut transactions = collector.enter("transactions");
+///     // Work attributed to transactions.
+///     {
+///         let _spans = transactions.enter("spans");
+///         // Work attributed to spans.
+///     }
+///     // Work attributed to transactions again.
+/// }
+/// ```
+pub struct CpuCollector<P: Producer> {
+    accountant: LocalUsageAccountant<P>,
+    resource_id: String,
+    /// The features of the scopes entered, innermost last.
+    scopes: Vec<String>,
+    /// The CPU time of the thread at the last scope boundary.
+    last_sample: Option<Duration>,
+    /// Thread CPU time is only meaningful on the thread it is read on.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<P: Producer> CpuCollector<P> {
+    /// Creates a collector recording into `accountant` the CPU time of
+    /// the current thread, against `resource_id`.
+    pub fn new(accountant: LocalUsageAccountant<P>, resource_id: &str) -> Self {
+        CpuCollector {
+            accountant,
+            resource_id: resource_id.to_owned(),
+            scopes: Vec::new(),
+            last_sample: None,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Attributes the CPU time of the thread to `app_feature` until the
+    /// returned guard is dropped.
+    pub fn enter(&mut self, app_feature: &str) -> CpuScope<'_, P> {
+        self.sample();
+        self.scopes.push(app_feature.to_owned());
+        CpuScope { collector: self }
+    }
+
+    /// Returns the feature CPU time is currently attributed to.
+    pub fn current_feature(&self) -> Option<&str> {
+        self.scopes.last().map(String::as_str)
+    }
+
+    /// Returns the accountant the CPU time is recorded into.
+    pub fn accountant(&mut self) -> &mut LocalUsageAccountant<P> {
+        &mut self.accountant
+    }
+
+    /// Records the CPU time consumed since the last scope boundary
+    /// against the innermost scope.
+    fn sample(&mut self) {
+        let Some(now) = thread_cpu_time() else {
+            return;
+        };
+        let previous = self.last_sample.replace(now);
+        let (Some(previous), Some(app_feature)) = (previous, self.scopes.last()) else {
+            return;
+        };
+        let nanos = u64::try_from(now.saturating_sub(previous).as_nanos()).unwrap_or(u64::MAX);
+        if nanos == 0 {
+            return;
+        }
+        if let Err(error) = self.accountant.record(
+            &self.resource_id,
+            app_feature,
+            nanos,
+            UsageUnit::CpuNanoseconds,
+        ) {
+            event!(Level::ERROR, "Failed to record CPU usage. {}", error);
+        }
+    }
+
+    fn exit(&mut self) {
+        self.sample();
+        self.scopes.pop();
+    }
+}
+
+/// A guard attributing the CPU time of the thread to a feature until it
+/// goes out of scope.
+///
+/// The guard dereferences to the collector, so nested scopes can be
+/// entered from it.
+pub struct CpuScope<'a, P: Producer> {
+    collector: &'a mut CpuCollector<P>,
+}
+
+impl<P: Producer> Deref for CpuScope<'_, P> {
+    type Target = CpuCollector<P>;
+
+    fn deref(&self) -> &Self::Target {
+        self.collector
+    }
+}
+
+impl<P: Producer> DerefMut for CpuScope<'_, P> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.collector
+    }
+}
+
+impl<P: Producer> Drop for CpuScope<'_, P> {
+    fn drop(&mut self) {
+        self.collector.exit();
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{DummyProducer, SharedUsageAccountant};
+
+    use super::*;
+
+    fn burn() {
+        let mut value: u64 = 0;
+        for i in 0..1_000_000u64 {
+            value = std::hint::black_box(value.wrapping_add(i));
+        }
+    }
+
+    #[test]
+    fn test_attributes_to_innermost_scope() {
+        let central = Arc::new(SharedUsageAccountant::new(DummyProducer::default(), None));
+        let mut collector = CpuCollector::new(LocalUsageAccountant::new(central.clone()), "relay");
+        burn();
+        {
+            let mut transactions = collector.enter("transactions");
+            burn();
+            {
+                let spans = transactions.enter("spans");
+                assert_eq!(spans.current_feature(), Some("spans"));
+                burn();
+            }
+            assert_eq!(transactions.current_feature(), Some("transactions"));
+            burn();
+        }
+        assert_eq!(collector.current_feature(), None);
+        collector.accountant().merge().unwrap();
+
+        let mut features: Vec<_> = central
+            .snapshot()
+            .entries
+            .into_iter()
+            .map(|(key, amount)| {
+                assert_eq!(key.resource_id, "relay");
+                assert_eq!(key.unit, UsageUnit::CpuNanoseconds);
+                assert!(amount > 0);
+                key.app_feature
+            })
+            .collect();
+        features.sort();
+        assert_eq!(features, vec!["spans", "transactions"]);
+    }
+}
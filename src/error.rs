@@ -0,0 +1,72 @@
+//! This module contains the errors returned by the accountants.
+//!
+
+use chrono::Duration;
+use thiserror::Error;
+
+use crate::{FlushReport, UsageUnit};
+
+/// Errors returned by the accountants. `E` is the error type of the
+/// producer.
+#[derive(Error, Debug)]
+pub enum AccountantError<E> {
+    /// Failed to serialize a usage message.
+    #[error("failed to encode usage message")]
+    Encoding(#[source] serde_json::Error),
+
+    /// The producer failed to send a usage message.
+    #[error("failed to produce usage message")]
+    Producer(#[source] E),
+
+    /// Usage was recorded with more tags than allowed.
+    #[error("too many tags: {count} provided, at most {max} allowed")]
+    TooManyTags { count: usize, max: usize },
+
+    /// Usage could not be converted to the canonical unit of its
+    /// dimension without overflowing.
+    #[error("{amount} {unit} overflows when normalized")]
+    UnitOverflow { amount: u64, unit: UsageUnit },
+
+    /// The feature requested is not available on this platform.
+    #[error("unsupported: {0}")]
+    Unsupported(&'static str),
+
+    /// The write-ahead log could not be read or written.
+    #[error("write-ahead log failure")]
+    Wal(#[source] std::io::Error),
+
+    /// A flush could not produce all the messages of the batch.
+    /// `sent` messages were handed to the producer, `retained` were put
+    /// back in the accumulator to be retried and `lost` were dropped.
+    /// `report` describes the messages that were sent. `source` is the
+    /// first failure encountered.
+    #[error("partial flush: {sent} messages sent, {retained} retained, {lost} lost")]
+    PartialFlush {
+        sent: usize,
+        retained: usize,
+        lost: usize,
+        report: Box<FlushReport>,
+        #[source]
+        source: Box<AccountantError<E>>,
+    },
+}
+
+/// Returned when declaring a unit conversion that would make a chain
+/// of conversions loop back to the unit it starts from.
+#[derive(Error, Clone, Debug, Eq, PartialEq)]
+#[error("the conversion of {unit} forms a cycle")]
+pub struct ConversionCycle {
+    /// The custom unit whose conversion was refused.
+    pub unit: String,
+}
+
+/// Returned when moving usage between accumulators whose buckets do
+/// not line up.
+#[derive(Error, Clone, Copy, Debug, Eq, PartialEq)]
+#[error("cannot merge usage bucketed every {from} into buckets of {into}")]
+pub struct GranularityMismatch {
+    /// The granularity of the accumulator the usage comes from.
+    pub from: Duration,
+    /// The granularity of the accumulator the usage goes to.
+    pub into: Duration,
+}
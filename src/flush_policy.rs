@@ -0,0 +1,188 @@
+//! This module contains the policies deciding when a batch is flushed.
+//!
+//! Flushing often keeps the latency of usage data low, flushing rarely
+//! produces fewer and larger messages. Policies let every application
+//! pick its trade-off, and can be combined with `AnyOf` and `AllOf`.
+//!
+
+use chrono::{DateTime, Duration, Utc};
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+
+use crate::accumulator::quantize;
+
+/// What a `FlushPolicy` looks at to decide whether a batch has to be
+/// flushed. Policies are only consulted for batches that are not empty.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BatchState {
+    /// Number of distinct keys in the batch.
+    pub keys: usize,
+    /// Estimated heap memory taken by the batch, in bytes.
+    pub estimated_bytes: usize,
+    /// When the first usage of the batch was recorded.
+    pub opened_at: DateTime<Utc>,
+    /// The granularity of the accumulator.
+    pub granularity: Duration,
+}
+
+/// Decides when the batch of an accountant is flushed.
+///
+/// The accountants consult the policy every time usage is recorded.
+/// Their `BatchSizeLimits` are checked along with it, a batch reaching
+/// one of the limits is flushed whatever the policy says.
+/// The `AsyncUsageAccountant` also consults it from its flush task, at
+/// the end of every bucket and every second while usage is pending.
+pub trait FlushPolicy: Send + Sync {
+    /// Returns true if the batch has to be flushed at `current_time`.
+    fn should_flush(&self, batch: &BatchState, current_time: DateTime<Utc>) -> bool;
+}
+
+/// Flushes once the bucket the batch was opened in is over. This is
+/// the default policy.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BucketBoundary;
+
+impl FlushPolicy for BucketBoundary {
+    fn should_flush(&self, batch: &BatchState, current_time: DateTime<Utc>) -> bool {
+        current_time - quantize(batch.opened_at, batch.granularity) >= batch.granularity
+    }
+}
+
+/// Flushes shortly after the bucket the batch was opened in is over,
+/// at a random delay of at most `max_jitter`.
+///
+/// Applications deployed together flush at the same time with
+/// `BucketBoundary`. The delay spreads their flushes, while each of
+/// them still flushes right after the bucket is complete. The delay is
+/// different for every bucket and every instance of the policy.
+///
+/// The flush happens at the first record, or `flush_if_ready` call,
+/// after that time, so idle applications should run a
+/// `BackgroundFlusher` with an interval shorter than `max_jitter`.
+#[derive(Clone, Debug)]
+pub struct JitteredBucketBoundary {
+    max_jitter: Duration,
+    seed: RandomState,
+}
+
+impl JitteredBucketBoundary {
+    pub fn new(max_jitter: Duration) -> Self {
+        Self {
+            max_jitter,
+            seed: RandomState::new(),
+        }
+    }
+
+    /// The delay applied to the flush of the bucket ending at
+    /// `bucket_end`.
+    fn jitter(&self, bucket_end: DateTime<Utc>) -> Duration {
+        let max_jitter = self.max_jitter.num_milliseconds();
+        if max_jitter <= 0 {
+            return Duration::zero();
+        }
+        let hash = self.seed.hash_one(bucket_end);
+        Duration::milliseconds((hash % (max_jitter as u64 + 1)) as i64)
+    }
+}
+
+impl FlushPolicy for JitteredBucketBoundary {
+    fn should_flush(&self, batch: &BatchState, current_time: DateTime<Utc>) -> bool {
+        let bucket_end = quantize(batch.opened_at, batch.granularity) + batch.granularity;
+        current_time >= bucket_end + self.jitter(bucket_end)
+    }
+}
+
+/// Flushes once the batch is older than the duration.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MaxAge(pub Duration);
+
+impl FlushPolicy for MaxAge {
+    fn should_flush(&self, batch: &BatchState, current_time: DateTime<Utc>) -> bool {
+        current_time - batch.opened_at >= self.0
+    }
+}
+
+/// Flushes once the batch holds that many keys.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MaxKeys(pub usize);
+
+impl FlushPolicy for MaxKeys {
+    fn should_flush(&self, batch: &BatchState, _current_time: DateTime<Utc>) -> bool {
+        batch.keys >= self.0
+    }
+}
+
+/// Flushes once the batch takes an estimated that many bytes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MaxBytes(pub usize);
+
+impl FlushPolicy for MaxBytes {
+    fn should_flush(&self, batch: &BatchState, _current_time: DateTime<Utc>) -> bool {
+        batch.estimated_bytes >= self.0
+    }
+}
+
+/// Flushes as soon as any of the policies says so.
+pub struct AnyOf(pub Vec<Box<dyn FlushPolicy>>);
+
+impl FlushPolicy for AnyOf {
+    fn should_flush(&self, batch: &BatchState, current_time: DateTime<Utc>) -> bool {
+        self.0
+            .iter()
+            .any(|policy| policy.should_flush(batch, current_time))
+    }
+}
+
+/// Flushes once all the policies say so.
+pub struct AllOf(pub Vec<Box<dyn FlushPolicy>>);
+
+impl FlushPolicy for AllOf {
+    fn should_flush(&self, batch: &BatchState, current_time: DateTime<Utc>) -> bool {
+        self.0
+            .iter()
+            .all(|policy| policy.should_flush(batch, current_time))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn test_policies() {
+        let at = |min, sec| Utc.with_ymd_and_hms(2023, 10, 8, 22, min, sec).unwrap();
+        let batch = BatchState {
+            keys: 10,
+            estimated_bytes: 1000,
+            opened_at: at(0, 50),
+            granularity: Duration::seconds(60),
+        };
+
+        assert!(!BucketBoundary.should_flush(&batch, at(0, 59)));
+        assert!(BucketBoundary.should_flush(&batch, at(1, 0)));
+        assert!(!MaxAge(Duration::seconds(30)).should_flush(&batch, at(1, 0)));
+        assert!(MaxAge(Duration::seconds(30)).should_flush(&batch, at(1, 20)));
+        assert!(MaxKeys(10).should_flush(&batch, at(0, 50)));
+        assert!(!MaxBytes(1001).should_flush(&batch, at(0, 50)));
+
+        let jittered = JitteredBucketBoundary::new(Duration::seconds(10));
+        assert!(!jittered.should_flush(&batch, at(0, 59)));
+        assert!(jittered.should_flush(&batch, at(1, 10)));
+        let jitters: Vec<_> = (0..100)
+            .map(|bucket| jittered.jitter(at(1, 0) + Duration::minutes(bucket)))
+            .collect();
+        assert!(jitters
+            .iter()
+            .all(|jitter| *jitter >= Duration::zero() && *jitter <= Duration::seconds(10)));
+        assert!(jitters.iter().any(|jitter| *jitter != jitters[0]));
+
+        let any = AnyOf(vec![Box::new(MaxKeys(100)), Box::new(BucketBoundary)]);
+        assert!(!any.should_flush(&batch, at(0, 55)));
+        assert!(any.should_flush(&batch, at(1, 0)));
+        let all = AllOf(vec![Box::new(MaxKeys(10)), Box::new(BucketBoundary)]);
+        assert!(!all.should_flush(&batch, at(0, 55)));
+        assert!(all.should_flush(&batch, at(1, 0)));
+    }
+}
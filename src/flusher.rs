@@ -0,0 +1,158 @@
+//! This module contains an optional background thread that flushes
+//! an accountant on a timer.
+//!
+//! Accountants only check whether the batch is ready when new usage
+//! is recorded. An application that stops recording would otherwise
+//! keep its last batch in memory until the accountant is dropped.
+//!
+
+use std::fmt::Display;
+use std::io;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tracing::{event, Level};
+
+use crate::lock::lock;
+use crate::{AccountantError, FlushReport, Producer, SharedUsageAccountant, UsageAccountant};
+
+/// An accountant that can be flushed from another thread, which is
+/// what a `BackgroundFlusher` needs.
+///
+/// It is implemented by `SharedUsageAccountant`, and by a
+/// `UsageAccountant` behind a `Mutex`.
+pub trait FlushIfReady {
+    type Error: Display;
+
+    /// Flushes the batch if it is ready. Returns `None` if it was not.
+    fn flush_if_ready(&self) -> Result<Option<FlushReport>, Self::Error>;
+}
+
+impl<P: Producer> FlushIfReady for SharedUsageAccountant<P> {
+    type Error = AccountantError<P::Error>;
+
+    fn flush_if_ready(&self) -> Result<Option<FlushReport>, Self::Error> {
+        SharedUsageAccountant::flush_if_ready(self)
+    }
+}
+
+impl<P: Producer> FlushIfReady for Mutex<UsageAccountant<P>> {
+    type Error = AccountantError<P::Error>;
+
+    fn flush_if_ready(&self) -> Result<Option<FlushReport>, Self::Error> {
+        lock(self).flush_if_ready()
+    }
+}
+
+/// Periodically flushes an accountant when its batch is ready, even if
+/// no usage is being recorded.
+///
+/// The thread is stopped and joined when the `BackgroundFlusher` is
+/// dropped. Dropping it does not flush the accountant, that happens
+/// when the accountant itself is dropped.
+///
+/// ```
+/// use sentry_usage_accountant::{BackgroundFlusher, Producer, UsageAccountant};
+/// use std::sync::{Arc, Mutex};
+/// use std::time::Duration;
+///
+/// fn start<P: Producer + Send + 'static>(
+///     accountant: UsageAccountant<P>,
+/// ) -> std::io::Result<BackgroundFlusher> {
+///     let accountant = Arc::new(Mutex::new(accountant));
+///     BackgroundFlusher::spawn(accountant, Duration::from_secs(1))
+/// }
+/// ```
+pub struct BackgroundFlusher {
+    stop: Option<mpsc::Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl BackgroundFlusher {
+    /// Spawns a thread that checks the accountant every `interval`
+    /// and flushes it if the batch is ready.
+    ///
+    /// Returns an error if the thread cannot be spawned.
+    pub fn spawn<A>(accountant: Arc<A>, interval: Duration) -> io::Result<Self>
+    where
+        A: FlushIfReady + Send + Sync + 'static,
+    {
+        let (stop, stop_rx) = mpsc::channel::<()>();
+        let handle = thread::Builder::new()
+            .name("usage-accountant-flusher".to_owned())
+            .spawn(move || {
+                // Stops on an explicit request or when the flusher is
+                // dropped.
+                while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(interval) {
+                    if let Err(error) = accountant.flush_if_ready() {
+                        event!(Level::ERROR, "Failed to flush usage batch. {}", error);
+                    }
+                }
+            })?;
+
+        Ok(BackgroundFlusher {
+            stop: Some(stop),
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for BackgroundFlusher {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration as ChronoDuration;
+
+    use super::*;
+    use crate::{DummyProducer, UsageUnit};
+
+    #[test]
+    fn test_flushes_idle_accountant() {
+        let accountant = Arc::new(SharedUsageAccountant::with_shards(
+            DummyProducer::default(),
+            Some(ChronoDuration::milliseconds(50)),
+            2,
+        ));
+        accountant
+            .record("resource_1", "transactions", 100, UsageUnit::Bytes)
+            .unwrap();
+
+        let flusher =
+            BackgroundFlusher::spawn(accountant.clone(), Duration::from_millis(10)).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        drop(flusher);
+
+        assert!(accountant.flush_if_ready().unwrap().is_none());
+        assert_eq!(accountant.producer().messages.len(), 1);
+    }
+
+    #[test]
+    fn test_flushes_idle_accountant_behind_mutex() {
+        let accountant = Arc::new(Mutex::new(UsageAccountant::new(
+            DummyProducer::default(),
+            Some(ChronoDuration::milliseconds(50)),
+        )));
+        accountant
+            .lock()
+            .unwrap()
+            .record("resource_1", "transactions", 100, UsageUnit::Bytes)
+            .unwrap();
+
+        let flusher =
+            BackgroundFlusher::spawn(accountant.clone(), Duration::from_millis(10)).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        drop(flusher);
+
+        let mut accountant = accountant.lock().unwrap();
+        assert!(accountant.flush_if_ready().unwrap().is_none());
+        assert_eq!(accountant.producer.messages.len(), 1);
+    }
+}
@@ -1,12 +1,18 @@
 use rdkafka::config::ClientConfig as RdKafkaConfig;
-use rdkafka::producer::{BaseRecord, ThreadedProducer};
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::producer::{BaseRecord, Producer as _, ThreadedProducer};
 use rdkafka::producer::{DeliveryResult, ProducerContext};
 use rdkafka::ClientContext;
 use std::collections::HashMap;
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
 use thiserror::Error;
 use tracing::{event, Level};
 
-use crate::Producer;
+use crate::lock::lock;
+use crate::{DeliveryReport, DeliveryStats, Producer};
 
 const DEFAULT_TOPIC_NAME: &str = "shared-resources-usage";
 
@@ -38,19 +44,52 @@ impl From<&KafkaConfig> for RdKafkaConfig {
     }
 }
 
-struct CaptureErrorContext;
+/// Logs delivery failures and counts the delivery outcomes. Messages
+/// carry their sequence number as delivery opaque.
+#[derive(Default)]
+struct CaptureErrorContext {
+    delivered: AtomicU64,
+    failed: AtomicU64,
+    /// Whether the sequence numbers of the messages are kept in
+    /// `deliveries` until they are taken.
+    tracking: AtomicBool,
+    deliveries: Mutex<DeliveryReport>,
+}
+
+impl CaptureErrorContext {
+    fn record(&self, sequence: usize, delivered: bool) {
+        let counter = if delivered {
+            &self.delivered
+        } else {
+            &self.failed
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        if !self.tracking.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let mut deliveries = lock(&self.deliveries);
+        if delivered {
+            deliveries.delivered.push(sequence as u64);
+        } else {
+            deliveries.failed.push(sequence as u64);
+        }
+    }
+}
 
 impl ClientContext for CaptureErrorContext {}
 
 impl ProducerContext for CaptureErrorContext {
-    type DeliveryOpaque = ();
+    type DeliveryOpaque = usize;
 
-    fn delivery(&self, result: &DeliveryResult, _delivery_opaque: Self::DeliveryOpaque) {
+    fn delivery(&self, result: &DeliveryResult, sequence: Self::DeliveryOpaque) {
         match result {
             Ok(_) => {
+                self.record(sequence, true);
                 event!(Level::DEBUG, "Message produced.")
             }
             Err((kafka_err, _)) => {
+                self.record(sequence, false);
                 event!(Level::ERROR, "Message production failed. {}", kafka_err)
             }
         }
@@ -60,17 +99,20 @@ impl ProducerContext for CaptureErrorContext {
 pub struct KafkaProducer {
     topic: String,
     producer: ThreadedProducer<CaptureErrorContext>,
+    /// The sequence number of the next message accepted.
+    sequence: usize,
 }
 
 impl KafkaProducer {
     pub fn new(config: KafkaConfig) -> KafkaProducer {
         let producer = RdKafkaConfig::from(&config)
-            .create_with_context(CaptureErrorContext)
+            .create_with_context(CaptureErrorContext::default())
             .expect("Producer creation error");
 
         KafkaProducer {
             topic: config.topic,
             producer,
+            sequence: 0,
         }
     }
 }
@@ -85,16 +127,72 @@ pub enum KafkaProducerError {
     /// Failed to create a kafka producer because of the invalid configuration.
     #[error("failed to create kafka producer: invalid kafka config")]
     InvalidConfig(#[source] rdkafka::error::KafkaError),
+
+    /// Failed to flush the messages in flight, for a reason other than
+    /// the timeout expiring.
+    #[error("failed to flush kafka messages")]
+    FlushFailed(#[source] rdkafka::error::KafkaError),
 }
 
 impl Producer for KafkaProducer {
     type Error = KafkaProducerError;
 
     fn send(&mut self, payload: Vec<u8>) -> Result<(), Self::Error> {
-        let record: BaseRecord<'_, [u8], [u8]> = BaseRecord::to(&self.topic).payload(&payload);
+        let record: BaseRecord<'_, [u8], [u8], usize> =
+            BaseRecord::with_opaque_to(&self.topic, self.sequence).payload(&payload);
         self.producer
             .send(record)
-            .map_err(|(error, _message)| KafkaProducerError::SendFailed(error))
+            .map_err(|(error, _message)| KafkaProducerError::SendFailed(error))?;
+        self.sequence += 1;
+        Ok(())
+    }
+
+    /// Waits for the in-flight messages of the `ThreadedProducer` to be
+    /// delivered. Messages still in flight after `timeout` are reported
+    /// as pending.
+    ///
+    /// The delivered and failed counts are cumulative since the
+    /// producer was created.
+    fn drain(&mut self, timeout: Duration) -> Result<Option<DeliveryStats>, Self::Error> {
+        match self.producer.flush(timeout) {
+            Ok(()) => {}
+            // Reported through the pending count.
+            Err(KafkaError::Flush(RDKafkaErrorCode::OperationTimedOut)) => {}
+            Err(error) => return Err(KafkaProducerError::FlushFailed(error)),
+        }
+
+        let context = self.producer.context();
+        Ok(Some(DeliveryStats {
+            delivered: context.delivered.load(Ordering::Relaxed),
+            failed: context.failed.load(Ordering::Relaxed),
+            pending: self.producer.in_flight_count().max(0) as u64,
+        }))
+    }
+
+    fn take_deliveries(&mut self) -> Option<DeliveryReport> {
+        let mut deliveries = lock(&self.producer.context().deliveries);
+        Some(mem::take(&mut *deliveries))
+    }
+
+    fn track_deliveries(&mut self) {
+        self.producer
+            .context()
+            .tracking
+            .store(true, Ordering::Relaxed);
+    }
+}
+
+/// `ThreadedProducer::send` only enqueues the message and never
+/// blocks, so the same producer can be used by the async accountant.
+#[cfg(feature = "tokio")]
+impl crate::AsyncProducer for KafkaProducer {
+    type Error = KafkaProducerError;
+
+    fn send(
+        &mut self,
+        payload: Vec<u8>,
+    ) -> impl std::future::Future<Output = Result<(), Self::Error>> + Send {
+        std::future::ready(Producer::send(self, payload))
     }
 }
 
@@ -124,4 +222,29 @@ mod tests {
             Some("1000000")
         );
     }
+
+    #[test]
+    fn test_deliveries_kept_only_when_tracked() {
+        let context = CaptureErrorContext::default();
+        for sequence in 0..10_000 {
+            context.record(sequence, sequence % 10 != 0);
+        }
+        assert_eq!(context.delivered.load(Ordering::Relaxed), 9_000);
+        assert_eq!(context.failed.load(Ordering::Relaxed), 1_000);
+        assert_eq!(
+            *context.deliveries.lock().unwrap(),
+            DeliveryReport::default()
+        );
+
+        context.tracking.store(true, Ordering::Relaxed);
+        context.record(10_000, true);
+        context.record(10_001, false);
+        assert_eq!(
+            *context.deliveries.lock().unwrap(),
+            DeliveryReport {
+                delivered: vec![10_000],
+                failed: vec![10_001],
+            }
+        );
+    }
 }
@@ -28,6 +28,18 @@
 //! Accumulating data locally is critical to reduce the performance impact
 //! of this library to a minimum and reduce the amount of Kafka messages.
 //!
+//! Applications recording usage from many threads can use the
+//! `SharedUsageAccountant`, which can be shared across threads and
+//! produces the same messages as the `UsageAccountant`. A
+//! `BackgroundFlusher` can be attached to it, or to a `UsageAccountant`
+//! behind a `Mutex`, so the last batch is produced even when the
+//! application stops recording. Threads
+//! recording at a very high rate can each own a `LocalUsageAccountant`,
+//! which accumulates without locking and periodically merges into a
+//! `SharedUsageAccountant`. Tokio based
+//! services can enable the `tokio` feature and use the
+//! `AsyncUsageAccountant`, which flushes from a background task.
+//!
 //! # Example
 //!
 //! ```no_run
@@ -55,12 +67,57 @@
 
 mod accountant;
 mod accumulator;
+mod aggregation;
+mod allocator;
+#[cfg(feature = "tokio")]
+mod async_accountant;
+mod batch;
+mod cardinality;
+mod clock;
+mod cpu;
+mod cpu_collector;
+mod error;
+mod flush_policy;
+mod flusher;
 #[cfg(feature = "kafka")]
 mod kafka;
+mod local;
+mod lock;
+mod overflow;
 mod producer;
+mod report;
+mod retry;
+mod shared;
+mod snapshot;
+mod timer;
+mod timestamp;
+mod unit;
+mod wal;
 
 pub use accountant::*;
+pub use accumulator::{UsageAccumulator, UsageKey};
+pub use aggregation::*;
+pub use allocator::*;
+#[cfg(feature = "tokio")]
+pub use async_accountant::*;
+pub use batch::*;
+pub use cardinality::*;
+pub use clock::*;
+pub use cpu_collector::*;
+pub use error::*;
+pub use flush_policy::*;
+pub use flusher::*;
 #[cfg(feature = "kafka")]
 pub use kafka::*;
+pub use local::*;
+pub use overflow::*;
 #[doc(inline)]
 pub use producer::*;
+pub use report::*;
+pub use retry::*;
+pub use shared::*;
+pub use snapshot::*;
+pub use timer::*;
+pub use timestamp::*;
+pub use unit::*;
+pub use wal::*;
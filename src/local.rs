@@ -0,0 +1,273 @@
+//! This module contains a thread-local front end to the
+//! `SharedUsageAccountant`.
+//!
+//! Threads recording at a very high rate still contend on the shard
+//! locks of the shared accountant. A `LocalUsageAccountant` is owned by
+//! a single thread or task, accumulates behind a lock of its own, and
+//! periodically merges what it accumulated into the shared accountant,
+//! which owns the producer.
+//!
+
+use chrono::{DateTime, Duration, Utc};
+use std::sync::{Arc, Mutex};
+
+use crate::accountant::{normalize, tag_set};
+use crate::accumulator::UsageAccumulator;
+use crate::lock::lock;
+use crate::{AccountantError, Producer, SharedUsageAccountant, UsageUnit};
+
+/// Accumulates the usage of one thread and merges it into a
+/// `SharedUsageAccountant`.
+///
+/// Usage is recorded with the units, tags and timestamp policies of the
+/// shared accountant, and merged into it every `merge_interval`, at the
+/// first record after that time. A thread that stops recording does
+/// not hold its usage back: the shared accountant collects it once the
+/// merge interval elapsed, when it checks whether it is ready to flush,
+/// for example from a `BackgroundFlusher`. The merge interval only adds
+/// to the latency of the usage. Whatever is left is merged when the
+/// local accountant is dropped.
+///
+/// ```
+/// use sentry_usage_accountant::{LocalUsageAccountant, Producer, SharedUsageAccountant, UsageUnit};
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// fn spawn_worker<P: Producer + Send + 'static>(central: Arc<SharedUsageAccountant<P>>) {
+///     thread::spawn(move || {
+///         let mut accountant = LocalUsageAccountant::new(central);
+///         for _ in 0..1000 {
+///             let _ = accountant.record("resource_1", "transactions", 100, UsageUnit::Bytes);
+///         }
+///     });
+/// }
+/// ```
+pub struct LocalUsageAccountant<P: Producer> {
+    /// Shared with `central`, which collects it when this thread is
+    /// idle. Otherwise only locked by this thread.
+    state: Arc<Mutex<LocalState>>,
+    central: Arc<SharedUsageAccountant<P>>,
+}
+
+/// The usage of a `LocalUsageAccountant` not merged yet.
+pub(crate) struct LocalState {
+    pub(crate) accumulator: UsageAccumulator,
+    pub(crate) merge_interval: Duration,
+    pub(crate) last_merge: DateTime<Utc>,
+}
+
+impl LocalState {
+    /// Whether the usage should be merged into the shared accountant.
+    pub(crate) fn merge_due(&self, current_time: DateTime<Utc>) -> bool {
+        current_time - self.last_merge >= self.merge_interval
+    }
+}
+
+impl<P: Producer> LocalUsageAccountant<P> {
+    /// Creates a local accountant merging into `central` every second.
+    pub fn new(central: Arc<SharedUsageAccountant<P>>) -> Self {
+        let state = Arc::new(Mutex::new(LocalState {
+            accumulator: central.local_accumulator(),
+            merge_interval: Duration::seconds(1),
+            last_merge: central.clock.now(),
+        }));
+        central.register_local(&state);
+        LocalUsageAccountant { state, central }
+    }
+
+    /// Sets how often the usage is merged into the shared accountant.
+    pub fn with_merge_interval(self, merge_interval: Duration) -> Self {
+        lock(&self.state).merge_interval = merge_interval;
+        self
+    }
+
+    /// Records an amount of usage for a resource, and app_feature. See
+    /// `UsageAccountant::record`.
+    ///
+    /// It merges the usage into the shared accountant if the merge
+    /// interval elapsed.
+    pub fn record(
+        &mut self,
+        resource_id: &str,
+        app_feature: &str,
+        amount: u64,
+        unit: UsageUnit,
+    ) -> Result<(), AccountantError<P::Error>> {
+        let (amount, unit) = normalize(self.central.units.as_ref(), amount, unit)?;
+        let current_time = self.central.clock.now();
+        lock(&self.state)
+            .accumulator
+            .record(current_time, resource_id, app_feature, amount, unit);
+        self.merge_if_due(current_time)
+    }
+
+    /// Records an amount of usage for a resource, and app_feature
+    /// that happened at `timestamp`. See `UsageAccountant::record_at`,
+    /// with the `TimestampPolicy` of the shared accountant. It merges
+    /// like `record`.
+    pub fn record_at(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        resource_id: &str,
+        app_feature: &str,
+        amount: u64,
+        unit: UsageUnit,
+    ) -> Result<(), AccountantError<P::Error>> {
+        let (amount, unit) = normalize(self.central.units.as_ref(), amount, unit)?;
+        let current_time = self.central.clock.now();
+        if let Some(usage_time) = self.central.timestamp_policy.apply(timestamp, current_time) {
+            lock(&self.state).accumulator.record_received(
+                current_time,
+                usage_time,
+                resource_id,
+                app_feature,
+                amount,
+                unit,
+            );
+        }
+        self.merge_if_due(current_time)
+    }
+
+    /// Records an amount of usage for a resource, and app_feature
+    /// broken down by additional dimensions. See
+    /// `UsageAccountant::record_with_tags`, it merges like `record`.
+    pub fn record_with_tags(
+        &mut self,
+        resource_id: &str,
+        app_feature: &str,
+        amount: u64,
+        unit: UsageUnit,
+        tags: &[(&str, &str)],
+    ) -> Result<(), AccountantError<P::Error>> {
+        let (amount, unit) = normalize(self.central.units.as_ref(), amount, unit)?;
+        let current_time = self.central.clock.now();
+        let tags = tag_set(tags, self.central.max_tags)?;
+        let mut state = lock(&self.state);
+        let mut key = state
+            .accumulator
+            .key(current_time, resource_id, app_feature, unit);
+        key.tags = tags;
+        state.accumulator.add(current_time, key, amount);
+        drop(state);
+        self.merge_if_due(current_time)
+    }
+
+    /// Merges the usage accumulated so far into the shared accountant,
+    /// which flushes it if its batch is ready.
+    ///
+    /// This method is called automatically when the local accountant
+    /// goes out of scope.
+    pub fn merge(&mut self) -> Result<(), AccountantError<P::Error>> {
+        let mut state = lock(&self.state);
+        state.last_merge = self.central.clock.now();
+        self.central.absorb(&mut state.accumulator)
+    }
+
+    fn merge_if_due(
+        &mut self,
+        current_time: DateTime<Utc>,
+    ) -> Result<(), AccountantError<P::Error>> {
+        if lock(&self.state).merge_due(current_time) {
+            self.merge()?;
+        }
+        Ok(())
+    }
+}
+
+impl<P: Producer> Drop for LocalUsageAccountant<P> {
+    fn drop(&mut self) {
+        let _ = self.merge();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use crate::{DummyProducer, MockClock};
+
+    use super::*;
+
+    #[test]
+    fn test_merges_into_shared_accountant() {
+        let start = Utc.with_ymd_and_hms(2023, 10, 8, 22, 15, 10).unwrap();
+        let clock = MockClock::new(start);
+        let central = Arc::new(
+            SharedUsageAccountant::new(DummyProducer::default(), None).with_clock(clock.clone()),
+        );
+        let amount = |central: &SharedUsageAccountant<DummyProducer>| -> u64 {
+            central
+                .snapshot()
+                .entries
+                .iter()
+                .map(|(_, amount)| amount)
+                .sum()
+        };
+
+        let mut first = LocalUsageAccountant::new(central.clone());
+        let mut second = LocalUsageAccountant::new(central.clone());
+        first
+            .record("resource_1", "transactions", 100, UsageUnit::Bytes)
+            .unwrap();
+        second
+            .record("resource_1", "transactions", 50, UsageUnit::Bytes)
+            .unwrap();
+        assert_eq!(amount(&central), 0);
+
+        clock.advance(Duration::seconds(1));
+        first
+            .record("resource_1", "transactions", 100, UsageUnit::Bytes)
+            .unwrap();
+        assert_eq!(amount(&central), 200);
+        drop(second);
+        let snapshot = central.snapshot();
+        assert_eq!(snapshot.entries.len(), 1);
+        assert_eq!(snapshot.entries[0].1, 250);
+
+        clock.advance(Duration::minutes(1));
+        first
+            .record("resource_1", "transactions", 10, UsageUnit::Bytes)
+            .unwrap();
+        // Merged into the bucket that was ready and flushed with it.
+        assert_eq!(central.producer().messages.len(), 2);
+        assert!(central.snapshot().is_empty());
+    }
+
+    #[test]
+    fn test_collects_idle_thread() {
+        let start = Utc.with_ymd_and_hms(2023, 10, 8, 22, 15, 10).unwrap();
+        let clock = MockClock::new(start);
+        let central = Arc::new(
+            SharedUsageAccountant::new(DummyProducer::default(), None).with_clock(clock.clone()),
+        );
+
+        let (recorded, idle) = std::sync::mpsc::channel();
+        let (done, stop) = std::sync::mpsc::channel::<()>();
+        let worker = {
+            let central = central.clone();
+            std::thread::spawn(move || {
+                let mut accountant = LocalUsageAccountant::new(central);
+                accountant
+                    .record("resource_1", "transactions", 100, UsageUnit::Bytes)
+                    .unwrap();
+                recorded.send(()).unwrap();
+                // Stays alive without recording anything else.
+                let _ = stop.recv();
+            })
+        };
+        idle.recv().unwrap();
+
+        // Not collected before the merge interval elapsed.
+        assert!(central.flush_if_ready().unwrap().is_none());
+        assert!(central.snapshot().is_empty());
+
+        clock.advance(Duration::minutes(1));
+        let report = central.flush_if_ready().unwrap().unwrap();
+        assert_eq!(report.messages, 1);
+        assert_eq!(central.producer().messages.len(), 1);
+
+        drop(done);
+        worker.join().unwrap();
+        assert_eq!(central.producer().messages.len(), 1);
+    }
+}
@@ -0,0 +1,14 @@
+//! This module contains the helper every thread-safe part of the crate
+//! locks its mutexes with.
+//!
+
+use std::sync::{Mutex, MutexGuard};
+
+/// Locks a mutex ignoring poisoning. A panic in another thread while
+/// recording cannot leave the accumulator in an inconsistent state,
+/// so there is no reason to stop accounting.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
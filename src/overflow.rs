@@ -0,0 +1,19 @@
+//! This module contains the policy deciding what happens when usage
+//! accumulated under a key does not fit in a `u64` anymore.
+//!
+
+/// What the accumulator does when adding to the amount of a key would
+/// overflow.
+///
+/// Byte-second counters can get there over long buckets. Whatever the
+/// policy, every overflow is counted and exposed by the accountants.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum OverflowPolicy {
+    /// The amount stays at `u64::MAX`, the excess is lost.
+    #[default]
+    Saturate,
+    /// A message with an amount of `u64::MAX` is split off and the
+    /// excess keeps accumulating, so nothing is lost. Consumers have to
+    /// sum the messages of a key.
+    Split,
+}
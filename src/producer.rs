@@ -5,6 +5,8 @@
 //!
 //! It also simplify unit tests.
 
+use std::time::Duration;
+
 /// A Producer trait.
 ///
 /// We do not neet to set headers or key for this data.
@@ -12,6 +14,32 @@ pub trait Producer {
     type Error;
 
     fn send(&mut self, payload: Vec<u8>) -> Result<(), Self::Error>;
+
+    /// Waits for the messages handed to `send` to be delivered, for at
+    /// most `timeout`, and returns the delivery outcome.
+    ///
+    /// Producers that deliver messages synchronously in `send` do not
+    /// need to override this. They return `None`, which means every
+    /// message sent is considered delivered.
+    fn drain(&mut self, _timeout: Duration) -> Result<Option<DeliveryStats>, Self::Error> {
+        Ok(None)
+    }
+
+    /// Returns the messages whose delivery outcome became known since
+    /// the last call. Messages are numbered from zero in the order
+    /// `send` accepted them.
+    ///
+    /// Producers that deliver messages synchronously in `send` do not
+    /// need to override this. They return `None`, which means every
+    /// message sent is considered delivered.
+    fn take_deliveries(&mut self) -> Option<DeliveryReport> {
+        None
+    }
+
+    /// Asks the producer to remember the outcome of every message until
+    /// `take_deliveries` is called. It is only asked when someone takes
+    /// the deliveries, so producers keep nothing before that.
+    fn track_deliveries(&mut self) {}
 }
 
 impl<T, P> Producer for T
@@ -24,19 +52,63 @@ where
     fn send(&mut self, payload: Vec<u8>) -> Result<(), Self::Error> {
         (**self).send(payload)
     }
+
+    fn drain(&mut self, timeout: Duration) -> Result<Option<DeliveryStats>, Self::Error> {
+        (**self).drain(timeout)
+    }
+
+    fn take_deliveries(&mut self) -> Option<DeliveryReport> {
+        (**self).take_deliveries()
+    }
+
+    fn track_deliveries(&mut self) {
+        (**self).track_deliveries()
+    }
+}
+
+/// Delivery outcome of the messages sent through a producer that
+/// delivers them asynchronously, since the producer was created.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DeliveryStats {
+    /// Messages acknowledged by the broker.
+    pub delivered: u64,
+    /// Messages the producer gave up on.
+    pub failed: u64,
+    /// Messages still waiting for an acknowledgement.
+    pub pending: u64,
+}
+
+/// The messages whose delivery outcome a producer learned, by their
+/// sequence number.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DeliveryReport {
+    /// Messages acknowledged by the broker.
+    pub delivered: Vec<u64>,
+    /// Messages the producer gave up on.
+    pub failed: Vec<u64>,
 }
 
 #[cfg(test)]
 #[derive(Debug, Default)]
 pub(crate) struct DummyProducer {
     pub messages: Vec<Vec<u8>>,
+    /// When set, sending fails once this many messages were sent.
+    pub send_limit: Option<usize>,
 }
 
+#[cfg(test)]
+#[derive(thiserror::Error, Debug)]
+#[error("dummy producer send limit reached")]
+pub(crate) struct DummyProducerError;
+
 #[cfg(test)]
 impl Producer for DummyProducer {
-    type Error = std::convert::Infallible;
+    type Error = DummyProducerError;
 
     fn send(&mut self, payload: Vec<u8>) -> Result<(), Self::Error> {
+        if self.send_limit == Some(self.messages.len()) {
+            return Err(DummyProducerError);
+        }
         self.messages.push(payload);
         Ok(())
     }
@@ -0,0 +1,57 @@
+//! This module contains the report describing what a flush produced.
+//!
+
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet};
+
+use crate::accumulator::UsageKey;
+use crate::UsageUnit;
+
+/// Describes the messages produced by a flush, so applications can
+/// monitor the volume of usage data they emit.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FlushReport {
+    /// Number of messages handed to the producer.
+    pub messages: usize,
+    /// Total amount produced for each unit. Saturates at `u64::MAX`.
+    pub amounts: HashMap<UsageUnit, u64>,
+    /// Number of distinct `(resource_id, app_feature)` pairs.
+    pub resource_features: usize,
+    /// The oldest and the most recent bucket produced, if any.
+    pub time_range: Option<(DateTime<Utc>, DateTime<Utc>)>,
+    /// Size of the encoded messages, in bytes.
+    pub bytes: usize,
+}
+
+/// Builds a `FlushReport` while the messages of a batch are produced.
+#[derive(Default)]
+pub(crate) struct FlushReportBuilder {
+    report: FlushReport,
+    resource_features: HashSet<(String, String)>,
+}
+
+impl FlushReportBuilder {
+    /// Accounts for a message that was handed to the producer.
+    pub(crate) fn add(&mut self, key: &UsageKey, amount: u64, bytes: usize) {
+        let report = &mut self.report;
+        report.messages += 1;
+        report.bytes += bytes;
+
+        let total = report.amounts.entry(key.unit.clone()).or_default();
+        *total = total.saturating_add(amount);
+
+        let timestamp = key.quantized_timestamp;
+        report.time_range = Some(match report.time_range {
+            Some((start, end)) => (start.min(timestamp), end.max(timestamp)),
+            None => (timestamp, timestamp),
+        });
+
+        self.resource_features
+            .insert((key.resource_id.clone(), key.app_feature.clone()));
+    }
+
+    pub(crate) fn build(mut self) -> FlushReport {
+        self.report.resource_features = self.resource_features.len();
+        self.report
+    }
+}
@@ -0,0 +1,28 @@
+//! This module contains the policy deciding what happens to usage the
+//! producer failed to send.
+//!
+
+use chrono::Duration;
+
+/// Decides how long usage that could not be produced is kept in the
+/// accumulator to be retried at the following flushes.
+///
+/// An entry is dropped once it failed `max_attempts` flushes or once
+/// it is retried for longer than `max_age`, measured from its first
+/// failure rather than from its bucket, so old usage recorded with
+/// `record_at` gets retried too. Setting `max_attempts` to zero drops
+/// failed entries right away.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub max_age: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            max_age: Duration::minutes(10),
+        }
+    }
+}
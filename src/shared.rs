@@ -0,0 +1,819 @@
+//! This module contains a thread-safe flavor of the accountant.
+//!
+//! The `SharedUsageAccountant` can be shared across worker threads
+//! (for example behind an `Arc`) and recorded into through a shared
+//! reference. Usage is spread across a number of independently locked
+//! accumulators (shards) so threads recording different features
+//! rarely contend on the same lock.
+//!
+
+use chrono::{DateTime, Duration, Utc};
+use std::collections::hash_map::Entry;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError, Weak};
+use std::thread;
+use std::time::Instant;
+
+use crate::accountant::{normalize, produce_batch, shutdown_flush, tag_set, BatchOutcome};
+use crate::accumulator::{UsageAccumulator, UsageKey};
+use crate::cardinality::KeyBudget;
+use crate::local::LocalState;
+use crate::lock::lock;
+use crate::{
+    AccountantError, Aggregation, BatchSizeLimits, CardinalityLimits, Clock, FlushPolicy,
+    FlushReport, OverflowPolicy, Producer, RetryPolicy, ShutdownSummary, SystemClock,
+    TimestampPolicy, UnitRegistry, UsageSnapshot, UsageUnit, DEFAULT_MAX_TAGS,
+};
+
+const DEFAULT_SHARDS: usize = 16;
+
+/// A `UsageAccountant` that can be used concurrently from many threads.
+///
+/// Each `(resource_id, app_feature)` pair is always routed to the same
+/// shard, so merging the shards at flush time produces exactly the
+/// same messages the single threaded `UsageAccountant` would produce.
+///
+/// As for the `UsageAccountant`, this should be instantiated rarely,
+/// ideally once per application, and shared between threads.
+pub struct SharedUsageAccountant<P: Producer> {
+    shards: Vec<Mutex<UsageAccumulator>>,
+    hasher: RandomState,
+    producer: Mutex<P>,
+    pub(crate) timestamp_policy: TimestampPolicy,
+    retry_policy: RetryPolicy,
+    summary: Mutex<ShutdownSummary>,
+    pub(crate) max_tags: usize,
+    pub(crate) units: Option<UnitRegistry>,
+    pub(crate) clock: Arc<dyn Clock>,
+    /// The usage of the `LocalUsageAccountant`s merging into this one,
+    /// collected when they are idle.
+    locals: Mutex<Vec<Weak<Mutex<LocalState>>>>,
+    /// Set once `shutdown` flushed the accountant for the last time, so
+    /// dropping it does not flush again.
+    shut_down: AtomicBool,
+}
+
+#[cfg(feature = "kafka")]
+impl SharedUsageAccountant<crate::KafkaProducer> {
+    /// Instantiates a SharedUsageAccountant from a Kafka config object.
+    /// This initialization method lets the `SharedUsageAccountant`
+    /// create the producer and own it.
+    pub fn new_with_kafka(
+        producer_config: crate::KafkaConfig,
+        granularity: Option<Duration>,
+    ) -> SharedUsageAccountant<crate::KafkaProducer> {
+        SharedUsageAccountant::new(crate::KafkaProducer::new(producer_config), granularity)
+    }
+}
+
+impl<P: Producer> SharedUsageAccountant<P> {
+    /// Instantiates a SharedUsageAccountant with one shard per
+    /// available CPU.
+    pub fn new(producer: P, granularity: Option<Duration>) -> Self {
+        let shards = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(DEFAULT_SHARDS);
+        Self::with_shards(producer, granularity, shards)
+    }
+
+    /// Instantiates a SharedUsageAccountant with an explicit number
+    /// of shards. At least one shard is always created.
+    pub fn with_shards(producer: P, granularity: Option<Duration>, shards: usize) -> Self {
+        SharedUsageAccountant {
+            shards: (0..shards.max(1))
+                .map(|_| Mutex::new(UsageAccumulator::new(granularity)))
+                .collect(),
+            hasher: RandomState::new(),
+            producer: Mutex::new(producer),
+            timestamp_policy: TimestampPolicy::default(),
+            retry_policy: RetryPolicy::default(),
+            summary: Mutex::new(ShutdownSummary::default()),
+            max_tags: DEFAULT_MAX_TAGS,
+            units: None,
+            clock: Arc::new(SystemClock),
+            locals: Mutex::new(Vec::new()),
+            shut_down: AtomicBool::new(false),
+        }
+    }
+
+    /// Sets the clock the current time is read from, the system clock
+    /// by default. Meant for tests, with a `MockClock`.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Sets how the amounts recorded with `unit` are combined within a
+    /// bucket. Units are summed unless configured otherwise.
+    pub fn with_aggregation(self, unit: UsageUnit, aggregation: Aggregation) -> Self {
+        for shard in &self.shards {
+            lock(shard).set_aggregation(unit.clone(), aggregation);
+        }
+        self
+    }
+
+    /// Makes the accountant convert usage to the canonical unit of its
+    /// dimension before accumulating it, so usage recorded in
+    /// compatible units is aggregated together. Custom units are
+    /// converted according to `units`.
+    ///
+    /// Recording fails with `UnitOverflow` if the converted amount does
+    /// not fit in a `u64`.
+    pub fn with_unit_normalization(mut self, units: UnitRegistry) -> Self {
+        self.units = Some(units);
+        self
+    }
+
+    /// Sets what happens when the amount accumulated for a key does
+    /// not fit in a `u64`. Amounts saturate by default.
+    pub fn with_overflow_policy(self, overflow_policy: OverflowPolicy) -> Self {
+        for shard in &self.shards {
+            lock(shard).set_overflow_policy(overflow_policy);
+        }
+        self
+    }
+
+    /// Returns how many times the amount accumulated for a key
+    /// overflowed since the accountant was created.
+    pub fn overflows(&self) -> u64 {
+        self.shards
+            .iter()
+            .map(|shard| lock(shard).overflows())
+            .sum()
+    }
+
+    /// Caps the number of distinct keys in a batch, so a dynamic value
+    /// passed as app_feature or tag cannot flood the topic.
+    ///
+    /// Limits apply to the keys of all the shards together. Usage over
+    /// the limits that ends up in several shards under the same key is
+    /// combined when flushed.
+    pub fn with_cardinality_limits(self, cardinality_limits: CardinalityLimits) -> Self {
+        let key_budget = Arc::new(Mutex::new(KeyBudget::default()));
+        for shard in &self.shards {
+            let mut shard = lock(shard);
+            shard.set_cardinality_limits(cardinality_limits.clone());
+            shard.share_key_budget(key_budget.clone());
+        }
+        self
+    }
+
+    /// Returns how many records hit a cardinality limit since the
+    /// accountant was created.
+    pub fn limited_records(&self) -> u64 {
+        self.shards
+            .iter()
+            .map(|shard| lock(shard).limited_records())
+            .sum()
+    }
+
+    /// Returns a copy of the usage accumulated and not flushed yet,
+    /// without flushing it.
+    ///
+    /// Shards are copied one after the other, so usage recorded
+    /// concurrently may only be partially included.
+    pub fn snapshot(&self) -> UsageSnapshot {
+        let mut snapshot = UsageSnapshot::default();
+        for shard in &self.shards {
+            snapshot.merge(lock(shard).snapshot());
+        }
+        snapshot.entries = merge_duplicates(snapshot.entries);
+        snapshot
+    }
+
+    /// Returns the size of the buckets usage is accumulated in.
+    pub fn granularity(&self) -> Duration {
+        lock(&self.shards[0]).granularity()
+    }
+
+    /// Bounds the size of the batch, which is flushed as soon as it
+    /// reaches one of the limits instead of waiting for the
+    /// granularity to elapse.
+    ///
+    /// The limits are checked along with the flush policy, whichever
+    /// of the two is set first. They are split evenly between the
+    /// shards, so a shard holding its share of the keys or bytes
+    /// flushes the whole batch, which thus never exceeds the limits.
+    pub fn with_batch_size_limits(self, size_limits: BatchSizeLimits) -> Self {
+        let shards = self.shards.len();
+        let shard_limits = BatchSizeLimits {
+            max_keys: size_limits.max_keys.div_ceil(shards),
+            max_bytes: size_limits.max_bytes.div_ceil(shards),
+        };
+        for shard in &self.shards {
+            lock(shard).set_size_limits(shard_limits);
+        }
+        self
+    }
+
+    /// Sets the policy deciding when the batch is flushed. By default
+    /// it is flushed once the bucket it was opened in is over.
+    ///
+    /// The policy is consulted for each shard on its own, with the
+    /// state of that shard. The batch size limits still apply, a batch
+    /// reaching one of them is flushed whatever the policy says.
+    pub fn with_flush_policy(self, flush_policy: impl FlushPolicy + 'static) -> Self {
+        let flush_policy: Arc<dyn FlushPolicy> = Arc::new(flush_policy);
+        for shard in &self.shards {
+            lock(shard).set_flush_policy(flush_policy.clone());
+        }
+        self
+    }
+
+    /// Sets the maximum number of tags `record_with_tags` accepts.
+    pub fn with_max_tags(mut self, max_tags: usize) -> Self {
+        self.max_tags = max_tags;
+        self
+    }
+
+    /// Sets the policy applied to the timestamps passed to `record_at`.
+    pub fn with_timestamp_policy(mut self, timestamp_policy: TimestampPolicy) -> Self {
+        self.timestamp_policy = timestamp_policy;
+        self
+    }
+
+    /// Sets the policy deciding how long usage that could not be
+    /// produced is kept for later flushes.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Records an amount of usage for a resource, and app_feature. See
+    /// `UsageAccountant::record`.
+    ///
+    /// It flushes all the shards if the one this record lands in
+    /// is ready to be flushed.
+    pub fn record(
+        &self,
+        resource_id: &str,
+        app_feature: &str,
+        amount: u64,
+        unit: UsageUnit,
+    ) -> Result<(), AccountantError<P::Error>> {
+        let (amount, unit) = normalize(self.units.as_ref(), amount, unit)?;
+        let current_time = self.clock.now();
+        self.record_received(
+            current_time,
+            current_time,
+            resource_id,
+            app_feature,
+            amount,
+            unit,
+        )
+    }
+
+    /// Records an amount of usage for a resource, and app_feature
+    /// that happened at `timestamp`. See `UsageAccountant::record_at`,
+    /// it flushes like `record`.
+    pub fn record_at(
+        &self,
+        timestamp: DateTime<Utc>,
+        resource_id: &str,
+        app_feature: &str,
+        amount: u64,
+        unit: UsageUnit,
+    ) -> Result<(), AccountantError<P::Error>> {
+        let (amount, unit) = normalize(self.units.as_ref(), amount, unit)?;
+        let current_time = self.clock.now();
+        match self.timestamp_policy.apply(timestamp, current_time) {
+            Some(usage_time) => self.record_received(
+                current_time,
+                usage_time,
+                resource_id,
+                app_feature,
+                amount,
+                unit,
+            ),
+            None => Ok(()),
+        }
+    }
+
+    /// Records normalized usage that happened at `usage_time` but is
+    /// only being recorded at `received_time`.
+    fn record_received(
+        &self,
+        received_time: DateTime<Utc>,
+        usage_time: DateTime<Utc>,
+        resource_id: &str,
+        app_feature: &str,
+        amount: u64,
+        unit: UsageUnit,
+    ) -> Result<(), AccountantError<P::Error>> {
+        let key =
+            self.shard(resource_id, app_feature)
+                .key(usage_time, resource_id, app_feature, unit);
+        self.add(received_time, key, amount)
+    }
+
+    /// Records an amount of usage for a resource, and app_feature
+    /// broken down by additional dimensions. See
+    /// `UsageAccountant::record_with_tags`, it flushes like `record`.
+    pub fn record_with_tags(
+        &self,
+        resource_id: &str,
+        app_feature: &str,
+        amount: u64,
+        unit: UsageUnit,
+        tags: &[(&str, &str)],
+    ) -> Result<(), AccountantError<P::Error>> {
+        let (amount, unit) = normalize(self.units.as_ref(), amount, unit)?;
+        let current_time = self.clock.now();
+        let mut key =
+            self.shard(resource_id, app_feature)
+                .key(current_time, resource_id, app_feature, unit);
+        key.tags = tag_set(tags, self.max_tags)?;
+        self.add(current_time, key, amount)
+    }
+
+    /// Sets the number of bytes a resource, and app_feature currently
+    /// holds. See `UsageAccountant::set_gauge`.
+    ///
+    /// It flushes all the shards if the one the gauge lives in is ready
+    /// to be flushed.
+    pub fn set_gauge(
+        &self,
+        resource_id: &str,
+        app_feature: &str,
+        bytes: u64,
+    ) -> Result<(), AccountantError<P::Error>> {
+        let current_time = self.clock.now();
+        let should_flush = {
+            let mut shard = self.shard(resource_id, app_feature);
+            shard.set_gauge(
+                current_time,
+                resource_id,
+                app_feature,
+                BTreeMap::new(),
+                bytes,
+            );
+            shard.should_flush(current_time)
+        };
+        if should_flush {
+            self.try_flush()?;
+        }
+        Ok(())
+    }
+
+    /// Adds usage to the shard the key belongs to and flushes all the
+    /// shards if that one is ready to be flushed.
+    fn add(
+        &self,
+        current_time: DateTime<Utc>,
+        key: UsageKey,
+        amount: u64,
+    ) -> Result<(), AccountantError<P::Error>> {
+        let should_flush = {
+            let mut shard = self.shard(&key.resource_id, &key.app_feature);
+            shard.add(current_time, key, amount);
+            shard.should_flush(current_time)
+        };
+        if should_flush {
+            self.try_flush()?;
+        }
+        Ok(())
+    }
+
+    /// Creates an empty accumulator building the same keys as the
+    /// shards, for a `LocalUsageAccountant`.
+    pub(crate) fn local_accumulator(&self) -> UsageAccumulator {
+        lock(&self.shards[0]).empty_like()
+    }
+
+    /// Registers the usage of a `LocalUsageAccountant`, so it is
+    /// collected when the local accountant is idle.
+    pub(crate) fn register_local(&self, local: &Arc<Mutex<LocalState>>) {
+        lock(&self.locals).push(Arc::downgrade(local));
+    }
+
+    /// Moves the usage accumulated by a `LocalUsageAccountant` into the
+    /// shards and flushes them if one of those is ready to be flushed.
+    pub(crate) fn absorb(
+        &self,
+        local: &mut UsageAccumulator,
+    ) -> Result<(), AccountantError<P::Error>> {
+        if self.absorb_into_shards(local, self.clock.now()) {
+            self.try_flush()?;
+        }
+        Ok(())
+    }
+
+    /// Moves the usage accumulated by a `LocalUsageAccountant` into the
+    /// shards. Returns whether one of those is ready to be flushed.
+    fn absorb_into_shards(
+        &self,
+        local: &mut UsageAccumulator,
+        current_time: DateTime<Utc>,
+    ) -> bool {
+        let opened_at = local.opened_at().unwrap_or(current_time);
+        let mut should_flush = false;
+        for (key, amount) in local.flush_at(current_time) {
+            let mut shard = self.shard(&key.resource_id, &key.app_feature);
+            shard.absorb(opened_at, current_time, key, amount);
+            should_flush |= shard.should_flush(current_time);
+        }
+        should_flush
+    }
+
+    /// Moves the usage of the local accountants that did not merge for
+    /// their merge interval into the shards, or of all of them if
+    /// `all` is set. Local accountants busy recording merge on their
+    /// own and are skipped.
+    fn collect_locals(&self, current_time: DateTime<Utc>, all: bool) {
+        lock(&self.locals).retain(|local| {
+            let Some(local) = local.upgrade() else {
+                return false;
+            };
+            let mut state = match local.try_lock() {
+                Ok(state) => state,
+                Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+                Err(TryLockError::WouldBlock) => return true,
+            };
+            if all || state.merge_due(current_time) {
+                state.last_merge = current_time;
+                self.absorb_into_shards(&mut state.accumulator, current_time);
+            }
+            true
+        });
+    }
+
+    /// Flushes all the shards, unless another thread is already
+    /// flushing, as there is no point in waiting for it.
+    fn try_flush(&self) -> Result<(), AccountantError<P::Error>> {
+        match self.producer.try_lock() {
+            Ok(mut producer) => {
+                self.flush_shards(&mut *producer)?;
+            }
+            Err(TryLockError::Poisoned(poisoned)) => {
+                self.flush_shards(&mut *poisoned.into_inner())?;
+            }
+            Err(TryLockError::WouldBlock) => {}
+        }
+        Ok(())
+    }
+
+    /// Flushes all the shards if any of them is ready to be flushed.
+    ///
+    /// Unlike `record`, this does not need any new usage to arrive, so
+    /// it can be called periodically to make sure the last batch is
+    /// produced when the application goes quiet. The usage of idle
+    /// `LocalUsageAccountant`s is collected first. Returns the report
+    /// of the flush, if one happened.
+    pub fn flush_if_ready(&self) -> Result<Option<FlushReport>, AccountantError<P::Error>> {
+        let current_time = self.clock.now();
+        self.collect_locals(current_time, false);
+        let ready = self
+            .shards
+            .iter()
+            .any(|shard| lock(shard).should_flush(current_time));
+        if !ready {
+            return Ok(None);
+        }
+        self.flush().map(Some)
+    }
+
+    /// Forces a flush of all the shards. See `UsageAccountant::flush`.
+    pub fn flush(&self) -> Result<FlushReport, AccountantError<P::Error>> {
+        let mut producer = lock(&self.producer);
+        self.flush_shards(&mut *producer)
+    }
+
+    /// Flushes all the shards and waits, for at most `timeout`, for the
+    /// producer to deliver everything it was handed. See
+    /// `UsageAccountant::shutdown`.
+    ///
+    /// It is meant to be called once all the threads are done
+    /// recording. The usage of the `LocalUsageAccountant`s still alive
+    /// is part of the last flush too. Dropping the accountant afterwards
+    /// does not flush it again.
+    pub fn shutdown(
+        &self,
+        timeout: std::time::Duration,
+    ) -> Result<ShutdownSummary, AccountantError<P::Error>> {
+        let deadline = Instant::now() + timeout;
+        let mut producer = lock(&self.producer);
+        self.shut_down.store(true, Ordering::Relaxed);
+        let current_time = self.clock.now();
+        self.collect_locals(current_time, true);
+        for shard in &self.shards {
+            lock(shard).close_gauges(current_time);
+        }
+        let mut retained = shutdown_flush(self.flush_shards(&mut *producer));
+        let mut delivery = producer.drain(timeout).map_err(AccountantError::Producer)?;
+        // Draining makes room in the queue of the producer, which is
+        // the most likely reason for it to refuse messages.
+        while retained > 0 && Instant::now() < deadline {
+            retained = shutdown_flush(self.flush_shards(&mut *producer));
+            delivery = producer
+                .drain(deadline.saturating_duration_since(Instant::now()))
+                .map_err(AccountantError::Producer)?;
+        }
+        let unflushed: usize = self
+            .shards
+            .iter()
+            .map(|shard| lock(shard).flush_at(self.clock.now()).len())
+            .sum();
+
+        let mut summary = lock(&self.summary);
+        summary.lost += unflushed as u64;
+        Ok(summary.with_delivery(delivery))
+    }
+
+    #[cfg(test)]
+    pub(crate) fn producer(&self) -> MutexGuard<'_, P> {
+        lock(&self.producer)
+    }
+
+    fn flush_shards(&self, producer: &mut P) -> Result<FlushReport, AccountantError<P::Error>> {
+        let current_time = self.clock.now();
+        let mut batch = Vec::new();
+        for shard in &self.shards {
+            batch.extend(lock(shard).flush_at(current_time));
+        }
+        let batch = merge_duplicates(batch);
+        let mut outcome = produce_batch(producer, BatchOutcome::new(batch));
+
+        // Unsent entries go back to the shard they came from.
+        let mut unsent: Vec<_> = self.shards.iter().map(|_| Vec::new()).collect();
+        for (key, amount) in mem::take(&mut outcome.unsent) {
+            unsent[self.shard_index(&key.resource_id, &key.app_feature)].push((key, amount));
+        }
+        let current_time = self.clock.now();
+        let retained = self
+            .shards
+            .iter()
+            .zip(unsent)
+            .map(|(shard, entries)| lock(shard).retain(entries, &self.retry_policy, current_time))
+            .sum();
+
+        let mut summary = lock(&self.summary);
+        summary.sent += outcome.sent as u64;
+        summary.lost += outcome.lost(retained) as u64;
+        outcome.into_result(retained)
+    }
+
+    fn shard(&self, resource_id: &str, app_feature: &str) -> MutexGuard<'_, UsageAccumulator> {
+        lock(&self.shards[self.shard_index(resource_id, app_feature)])
+    }
+
+    fn shard_index(&self, resource_id: &str, app_feature: &str) -> usize {
+        let mut hasher = self.hasher.build_hasher();
+        resource_id.hash(&mut hasher);
+        app_feature.hash(&mut hasher);
+        hasher.finish() as usize % self.shards.len()
+    }
+}
+
+impl<P: Producer> Drop for SharedUsageAccountant<P> {
+    fn drop(&mut self) {
+        if !*self.shut_down.get_mut() {
+            let _ = self.flush();
+        }
+    }
+}
+
+/// Combines the entries several shards hold for the same key.
+///
+/// Keys are spread across shards by resource and app_feature, except
+/// the keys usage over the cardinality limits is recorded under, which
+/// any shard can hold. Amounts that overflow when combined are kept
+/// apart.
+fn merge_duplicates(entries: Vec<(UsageKey, u64)>) -> Vec<(UsageKey, u64)> {
+    let mut merged: HashMap<UsageKey, u64> = HashMap::with_capacity(entries.len());
+    let mut apart = Vec::new();
+    for (key, amount) in entries {
+        match merged.entry(key) {
+            Entry::Vacant(entry) => {
+                entry.insert(amount);
+            }
+            Entry::Occupied(mut entry) => match entry.key().aggregation.merge(*entry.get(), amount)
+            {
+                (value, false) => *entry.get_mut() = value,
+                (_, true) => apart.push((entry.key().clone(), amount)),
+            },
+        }
+    }
+    apart.extend(merged);
+    apart
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use std::sync::Arc;
+
+    use crate::accountant::Message;
+    use crate::{DummyProducer, MockClock, OutOfRangeAction, OTHER_FEATURE};
+
+    use super::*;
+
+    #[test]
+    fn test_shutdown_closes_gauges() {
+        let clock = MockClock::new(Utc.with_ymd_and_hms(2023, 10, 8, 22, 15, 10).unwrap());
+        let mut producer = DummyProducer::default();
+        let accountant = SharedUsageAccountant::new(&mut producer, None).with_clock(clock.clone());
+        accountant.set_gauge("resource_1", "cache", 1000).unwrap();
+        clock.advance(Duration::seconds(20));
+
+        let summary = accountant
+            .shutdown(std::time::Duration::from_secs(1))
+            .unwrap();
+        drop(accountant);
+        assert_eq!(summary.lost, 0);
+        assert_eq!(summary.sent, 1);
+        // Nothing is produced once the accountant is shut down.
+        assert_eq!(producer.messages.len(), 1);
+        let message: Message = serde_json::from_slice(&producer.messages[0]).unwrap();
+        assert_eq!(message.usage_unit, UsageUnit::BytesSec);
+        assert_eq!(message.amount, 20_000);
+    }
+
+    #[test]
+    fn test_record_ignores_timestamp_policy() {
+        let clock = MockClock::new(Utc.with_ymd_and_hms(2023, 10, 8, 22, 15, 10).unwrap());
+        // Drops the current time, only usage from before is accepted.
+        let accountant = SharedUsageAccountant::new(DummyProducer::default(), None)
+            .with_clock(clock.clone())
+            .with_timestamp_policy(TimestampPolicy {
+                max_age: Duration::hours(1),
+                max_future: Duration::minutes(-1),
+                action: OutOfRangeAction::Drop,
+            });
+
+        accountant
+            .record("resource_1", "transactions", 100, UsageUnit::Bytes)
+            .unwrap();
+        accountant
+            .record_at(
+                clock.now(),
+                "resource_1",
+                "transactions",
+                50,
+                UsageUnit::Bytes,
+            )
+            .unwrap();
+
+        accountant.flush().unwrap();
+        let producer = accountant.producer();
+        assert_eq!(producer.messages.len(), 1);
+        let message: Message = serde_json::from_slice(&producer.messages[0]).unwrap();
+        assert_eq!(message.amount, 100);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let accountant = SharedUsageAccountant::new(DummyProducer::default(), None);
+        assert!(accountant.snapshot().is_empty());
+
+        for feature in ["transactions", "spans", "profiles"] {
+            accountant
+                .record("resource_1", feature, 100, UsageUnit::Bytes)
+                .unwrap();
+        }
+        let mut features: Vec<_> = accountant
+            .snapshot()
+            .entries
+            .into_iter()
+            .map(|(key, amount)| (key.app_feature, amount))
+            .collect();
+        features.sort();
+        assert_eq!(
+            features,
+            vec![
+                ("profiles".to_string(), 100),
+                ("spans".to_string(), 100),
+                ("transactions".to_string(), 100),
+            ]
+        );
+        assert!(accountant.producer().messages.is_empty());
+    }
+
+    #[test]
+    fn test_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SharedUsageAccountant<DummyProducer>>();
+    }
+
+    #[test]
+    fn test_size_limits_span_shards() {
+        let accountant = SharedUsageAccountant::with_shards(DummyProducer::default(), None, 2)
+            .with_batch_size_limits(BatchSizeLimits {
+                max_keys: 4,
+                ..BatchSizeLimits::default()
+            });
+        // Three features in each shard, more than the limit together.
+        let features: Vec<_> = [0, 1]
+            .into_iter()
+            .flat_map(|index| {
+                (0..)
+                    .map(|feature| format!("feature_{feature}"))
+                    .filter(|feature| accountant.shard_index("resource_1", feature) == index)
+                    .take(3)
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        for feature in &features {
+            accountant
+                .record("resource_1", feature, 10, UsageUnit::Bytes)
+                .unwrap();
+            assert!(accountant.snapshot().entries.len() <= 4);
+        }
+        assert!(!accountant.producer().messages.is_empty());
+    }
+
+    #[test]
+    fn test_merges_shards_on_flush() {
+        let accountant = Arc::new(SharedUsageAccountant::with_shards(
+            DummyProducer::default(),
+            None,
+            4,
+        ));
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let accountant = accountant.clone();
+                thread::spawn(move || {
+                    for feature in ["transactions", "spans", "profiles"] {
+                        accountant
+                            .record("resource_1", feature, 10, UsageUnit::Bytes)
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        accountant.flush().unwrap();
+
+        let producer = accountant.producer();
+        let mut messages: Vec<Message> = producer
+            .messages
+            .iter()
+            .map(|payload| serde_json::from_slice(payload).unwrap())
+            .collect();
+        messages.sort_by(|a, b| a.app_feature.cmp(&b.app_feature));
+
+        assert_eq!(messages.len(), 3);
+        for (message, feature) in messages.iter().zip(["profiles", "spans", "transactions"]) {
+            assert_eq!(message.shared_resource_id, "resource_1");
+            assert_eq!(message.app_feature, feature);
+            assert_eq!(message.usage_unit, UsageUnit::Bytes);
+            assert_eq!(message.amount, 80);
+        }
+        drop(producer);
+
+        let summary = accountant
+            .shutdown(std::time::Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(summary.sent, 3);
+        assert_eq!(summary.delivered, 3);
+        assert_eq!(summary.lost, 0);
+    }
+
+    #[test]
+    fn test_cardinality_limits_span_shards() {
+        let accountant = SharedUsageAccountant::with_shards(DummyProducer::default(), None, 8)
+            .with_cardinality_limits(CardinalityLimits {
+                max_keys_per_resource: 1,
+                ..CardinalityLimits::default()
+            });
+        for i in 0..10 {
+            accountant
+                .record("resource_1", &format!("feature_{i}"), 10, UsageUnit::Bytes)
+                .unwrap();
+        }
+        accountant.flush().unwrap();
+
+        let producer = accountant.producer();
+        let mut messages: Vec<(String, u64)> = producer
+            .messages
+            .iter()
+            .map(|payload| {
+                let message: Message = serde_json::from_slice(payload).unwrap();
+                (message.app_feature, message.amount)
+            })
+            .collect();
+        messages.sort();
+        assert_eq!(
+            messages,
+            vec![
+                (OTHER_FEATURE.to_string(), 90),
+                ("feature_0".to_string(), 10),
+            ]
+        );
+        drop(producer);
+
+        // Flushing frees the budget.
+        accountant
+            .record("resource_1", "feature_9", 10, UsageUnit::Bytes)
+            .unwrap();
+        assert_eq!(accountant.snapshot().entries[0].0.app_feature, "feature_9");
+    }
+}
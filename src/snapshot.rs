@@ -0,0 +1,47 @@
+//! This module contains the read-only view of the usage accumulated
+//! and not flushed yet.
+//!
+
+use chrono::{DateTime, Duration, Utc};
+use std::ops::Range;
+
+use crate::UsageKey;
+
+/// A copy of the usage accumulated and not flushed yet, as returned by
+/// the `snapshot` method of the accumulator and the accountants.
+///
+/// Taking a snapshot does not change what is flushed, so it can be
+/// used to expose live usage or to check the pending usage in tests.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct UsageSnapshot {
+    /// The amount accumulated under every key, in no particular order.
+    /// A key that overflowed with `OverflowPolicy::Split` appears once
+    /// per message it will be produced as.
+    pub entries: Vec<(UsageKey, u64)>,
+    /// The time the buckets of the entries span, from the start of the
+    /// oldest to the end of the newest. `None` if there are no entries.
+    pub buckets: Option<Range<DateTime<Utc>>>,
+}
+
+impl UsageSnapshot {
+    pub(crate) fn new(entries: Vec<(UsageKey, u64)>, granularity: Duration) -> Self {
+        let start = entries.iter().map(|(key, _)| key.quantized_timestamp).min();
+        let end = entries.iter().map(|(key, _)| key.quantized_timestamp).max();
+        let buckets = start.zip(end).map(|(start, end)| start..end + granularity);
+        Self { entries, buckets }
+    }
+
+    /// Returns true if no usage is pending.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Adds the entries of another snapshot to this one.
+    pub(crate) fn merge(&mut self, other: UsageSnapshot) {
+        self.entries.extend(other.entries);
+        self.buckets = match (self.buckets.take(), other.buckets) {
+            (Some(a), Some(b)) => Some(a.start.min(b.start)..a.end.max(b.end)),
+            (a, b) => a.or(b),
+        };
+    }
+}
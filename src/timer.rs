@@ -0,0 +1,254 @@
+//! This module contains a scoped timer that records the time spent
+//! in a block of code.
+//!
+//! Measuring time by hand is error prone when the block returns early
+//! or fails with `?`. The timer records when it goes out of scope,
+//! whatever the path taken.
+//!
+
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::time::{Duration, Instant};
+use tracing::{event, Level};
+
+use crate::accountant::normalize;
+use crate::cpu::thread_cpu_time;
+use crate::{AccountantError, Producer, UsageAccountant, UsageUnit};
+
+/// Which time a `UsageTimer` measures.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TimerKind {
+    /// Wall clock time, including the time the thread is blocked.
+    /// Recorded as `Milliseconds`.
+    Wall,
+    /// CPU time consumed by the current thread, recorded as
+    /// `CpuNanoseconds`. Only available on unix platforms.
+    ThreadCpu,
+}
+
+#[derive(Clone, Copy)]
+enum Start {
+    Wall(Instant),
+    ThreadCpu(Duration),
+}
+
+impl Start {
+    /// Returns `None` if the time of this kind cannot be measured.
+    fn new(kind: TimerKind) -> Option<Self> {
+        match kind {
+            TimerKind::Wall => Some(Start::Wall(Instant::now())),
+            TimerKind::ThreadCpu => thread_cpu_time().map(Start::ThreadCpu),
+        }
+    }
+
+    fn elapsed(&self) -> Duration {
+        match self {
+            Start::Wall(start) => start.elapsed(),
+            Start::ThreadCpu(start) => thread_cpu_time()
+                .map(|now| now.saturating_sub(*start))
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Returns the time elapsed in the unit it is recorded in.
+    fn usage(&self) -> (u64, UsageUnit) {
+        let elapsed = self.elapsed();
+        match self {
+            Start::Wall(_) => (
+                u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX),
+                UsageUnit::Milliseconds,
+            ),
+            Start::ThreadCpu(_) => (
+                u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX),
+                UsageUnit::CpuNanoseconds,
+            ),
+        }
+    }
+}
+
+/// A guard that records the time elapsed since it was started when it
+/// goes out of scope.
+///
+/// The guard borrows the accountant mutably, but it dereferences to it
+/// so usage can still be recorded while the timer is running.
+///
+/// Usage is added to the accumulator when the guard is dropped, the
+/// batch is flushed by the following call to `record` or `flush`.
+/// The guard is dropped on the thread that started it.
+pub struct UsageTimer<'a, P: Producer> {
+    accountant: &'a mut UsageAccountant<P>,
+    resource_id: String,
+    app_feature: String,
+    start: Start,
+    cancelled: bool,
+    /// Thread CPU time is only meaningful on the thread it is read on,
+    /// so the timer cannot be moved to another thread.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<'a, P: Producer> UsageTimer<'a, P> {
+    pub(crate) fn new(
+        accountant: &'a mut UsageAccountant<P>,
+        resource_id: &str,
+        app_feature: &str,
+        kind: TimerKind,
+    ) -> Result<Self, AccountantError<P::Error>> {
+        let start = Start::new(kind).ok_or(AccountantError::Unsupported(
+            "thread CPU time is not available on this platform",
+        ))?;
+        Ok(Self::started(accountant, resource_id, app_feature, start))
+    }
+
+    /// Starts a wall time timer, which cannot fail.
+    pub(crate) fn wall(
+        accountant: &'a mut UsageAccountant<P>,
+        resource_id: &str,
+        app_feature: &str,
+    ) -> Self {
+        Self::started(
+            accountant,
+            resource_id,
+            app_feature,
+            Start::Wall(Instant::now()),
+        )
+    }
+
+    fn started(
+        accountant: &'a mut UsageAccountant<P>,
+        resource_id: &str,
+        app_feature: &str,
+        start: Start,
+    ) -> Self {
+        UsageTimer {
+            accountant,
+            resource_id: resource_id.to_owned(),
+            app_feature: app_feature.to_owned(),
+            start,
+            cancelled: false,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Returns the time measured so far.
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Stops the timer without recording anything.
+    pub fn cancel(mut self) {
+        self.cancelled = true;
+    }
+}
+
+impl<P: Producer> Deref for UsageTimer<'_, P> {
+    type Target = UsageAccountant<P>;
+
+    fn deref(&self) -> &Self::Target {
+        self.accountant
+    }
+}
+
+impl<P: Producer> DerefMut for UsageTimer<'_, P> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.accountant
+    }
+}
+
+impl<P: Producer> Drop for UsageTimer<'_, P> {
+    fn drop(&mut self) {
+        if self.cancelled {
+            return;
+        }
+        let (amount, unit) = self.start.usage();
+        let accountant = &mut *self.accountant;
+        match normalize::<P::Error>(accountant.units.as_ref(), amount, unit) {
+            Ok((amount, unit)) => accountant.accumulator.record(
+                accountant.clock.now(),
+                &self.resource_id,
+                &self.app_feature,
+                amount,
+                unit,
+            ),
+            Err(error) => event!(Level::ERROR, "Dropping timer usage. {}", error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::accountant::Message;
+    use crate::DummyProducer;
+
+    use super::*;
+
+    fn run(accountant: &mut UsageAccountant<DummyProducer>, fail: bool) -> Result<(), ()> {
+        let _timer = accountant.start_timer("resource_1", "transactions");
+        thread::sleep(Duration::from_millis(20));
+        if fail {
+            return Err(());
+        }
+        thread::sleep(Duration::from_millis(20));
+        Ok(())
+    }
+
+    #[test]
+    fn test_records_on_every_path() {
+        let mut accountant = UsageAccountant::new(DummyProducer::default(), None);
+        run(&mut accountant, false).unwrap();
+        run(&mut accountant, true).unwrap_err();
+        accountant.flush().unwrap();
+
+        let messages = &accountant.producer.messages;
+        assert_eq!(messages.len(), 1);
+        let message: Message = serde_json::from_slice(&messages[0]).unwrap();
+        assert_eq!(message.app_feature, "transactions");
+        assert_eq!(message.usage_unit, UsageUnit::Milliseconds);
+        assert!(message.amount >= 60);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut accountant = UsageAccountant::new(DummyProducer::default(), None);
+        let mut timer = accountant.start_timer("resource_1", "transactions");
+        timer
+            .record("resource_1", "spans", 100, UsageUnit::Bytes)
+            .unwrap();
+        timer.cancel();
+        accountant.flush().unwrap();
+
+        let messages = &accountant.producer.messages;
+        assert_eq!(messages.len(), 1);
+        let message: Message = serde_json::from_slice(&messages[0]).unwrap();
+        assert_eq!(message.app_feature, "spans");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_thread_cpu_ignores_sleep() {
+        let mut accountant = UsageAccountant::new(DummyProducer::default(), None);
+        let timer = accountant
+            .start_timer_with("resource_1", "transactions", TimerKind::ThreadCpu)
+            .unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(timer.elapsed() < Duration::from_millis(50));
+        drop(timer);
+        accountant.flush().unwrap();
+
+        let messages = &accountant.producer.messages;
+        assert_eq!(messages.len(), 1);
+        let message: Message = serde_json::from_slice(&messages[0]).unwrap();
+        assert_eq!(message.usage_unit, UsageUnit::CpuNanoseconds);
+    }
+
+    #[cfg(not(unix))]
+    #[test]
+    fn test_thread_cpu_unsupported() {
+        let mut accountant = UsageAccountant::new(DummyProducer::default(), None);
+        assert!(matches!(
+            accountant.start_timer_with("resource_1", "transactions", TimerKind::ThreadCpu),
+            Err(AccountantError::Unsupported(_))
+        ));
+    }
+}
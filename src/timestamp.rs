@@ -0,0 +1,118 @@
+//! This module contains the policy applied to usage recorded with an
+//! explicit timestamp.
+//!
+//! Consumers processing a backlog want usage attributed to the time the
+//! work was produced. Timestamps too far in the past or in the future
+//! are likely bogus, and the policy decides what happens to them.
+//!
+
+use chrono::{DateTime, Duration, Utc};
+
+/// What to do with usage whose timestamp is outside of the window
+/// accepted by a `TimestampPolicy`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OutOfRangeAction {
+    /// Move the timestamp to the closest edge of the window.
+    Clamp,
+    /// Discard the usage.
+    Drop,
+    /// Record the usage with its timestamp anyway.
+    Accept,
+}
+
+/// Decides which explicit timestamps are accepted when recording
+/// usage through `record_at`.
+///
+/// The window goes from `max_age` before the current time to
+/// `max_future` after it. The default policy accepts any timestamp.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TimestampPolicy {
+    pub max_age: Duration,
+    pub max_future: Duration,
+    pub action: OutOfRangeAction,
+}
+
+impl Default for TimestampPolicy {
+    fn default() -> Self {
+        Self {
+            max_age: Duration::hours(1),
+            max_future: Duration::minutes(1),
+            action: OutOfRangeAction::Accept,
+        }
+    }
+}
+
+impl TimestampPolicy {
+    /// Returns the timestamp the usage should be recorded with, or
+    /// `None` if the usage has to be dropped.
+    pub(crate) fn apply(
+        &self,
+        timestamp: DateTime<Utc>,
+        current_time: DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        let oldest = current_time - self.max_age;
+        let newest = current_time + self.max_future;
+        if oldest <= timestamp && timestamp <= newest {
+            return Some(timestamp);
+        }
+
+        match self.action {
+            OutOfRangeAction::Clamp => Some(timestamp.clamp(oldest, newest)),
+            OutOfRangeAction::Drop => None,
+            OutOfRangeAction::Accept => Some(timestamp),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn policy(action: OutOfRangeAction) -> TimestampPolicy {
+        TimestampPolicy {
+            max_age: Duration::minutes(10),
+            max_future: Duration::minutes(1),
+            action,
+        }
+    }
+
+    #[test]
+    fn test_in_range() {
+        let now = Utc.with_ymd_and_hms(2023, 10, 8, 22, 15, 25).unwrap();
+        let timestamp = Utc.with_ymd_and_hms(2023, 10, 8, 22, 10, 0).unwrap();
+        for action in [
+            OutOfRangeAction::Clamp,
+            OutOfRangeAction::Drop,
+            OutOfRangeAction::Accept,
+        ] {
+            assert_eq!(policy(action).apply(timestamp, now), Some(timestamp));
+        }
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        let now = Utc.with_ymd_and_hms(2023, 10, 8, 22, 15, 25).unwrap();
+        let old = Utc.with_ymd_and_hms(2023, 10, 8, 20, 0, 0).unwrap();
+        let future = Utc.with_ymd_and_hms(2023, 10, 8, 23, 0, 0).unwrap();
+
+        let clamp = policy(OutOfRangeAction::Clamp);
+        assert_eq!(
+            clamp.apply(old, now),
+            Some(Utc.with_ymd_and_hms(2023, 10, 8, 22, 5, 25).unwrap())
+        );
+        assert_eq!(
+            clamp.apply(future, now),
+            Some(Utc.with_ymd_and_hms(2023, 10, 8, 22, 16, 25).unwrap())
+        );
+
+        let drop = policy(OutOfRangeAction::Drop);
+        assert_eq!(drop.apply(old, now), None);
+        assert_eq!(drop.apply(future, now), None);
+
+        let accept = policy(OutOfRangeAction::Accept);
+        assert_eq!(accept.apply(old, now), Some(old));
+        assert_eq!(accept.apply(future, now), Some(future));
+    }
+}
@@ -0,0 +1,379 @@
+//! This module contains the units usage is recorded in and the
+//! registry describing what each of them measures.
+//!
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+use crate::ConversionCycle;
+
+/// The unit of measures we support when recording usage.
+///
+/// Units are serialized as their snake case name. Applications that
+/// need a unit not listed here can use `Custom`, which is serialized
+/// as the name it carries.
+///
+/// Units are identified by their name: a custom unit named like a
+/// built-in one is equal to the built-in one, and is accumulated and
+/// converted like it.
+#[derive(Clone, Debug)]
+pub enum UsageUnit {
+    Milliseconds,
+    Bytes,
+    BytesSec,
+    Microseconds,
+    Nanoseconds,
+    /// CPU time, as opposed to wall time, in nanoseconds.
+    CpuNanoseconds,
+    /// A number of events.
+    Count,
+    /// A number of requests.
+    Requests,
+    Seconds,
+    Kibibytes,
+    Custom(String),
+}
+
+impl UsageUnit {
+    /// The name of the unit, as it appears in the produced messages.
+    pub fn as_str(&self) -> &str {
+        match self {
+            UsageUnit::Milliseconds => "milliseconds",
+            UsageUnit::Bytes => "bytes",
+            UsageUnit::BytesSec => "bytes_sec",
+            UsageUnit::Microseconds => "microseconds",
+            UsageUnit::Nanoseconds => "nanoseconds",
+            UsageUnit::CpuNanoseconds => "cpu_nanoseconds",
+            UsageUnit::Count => "count",
+            UsageUnit::Requests => "requests",
+            UsageUnit::Seconds => "seconds",
+            UsageUnit::Kibibytes => "kibibytes",
+            UsageUnit::Custom(name) => name,
+        }
+    }
+
+    /// What the unit measures. Custom units have no dimension unless
+    /// declared in a `UnitRegistry`.
+    pub fn dimension(&self) -> Option<Dimension> {
+        match self {
+            UsageUnit::Seconds
+            | UsageUnit::Milliseconds
+            | UsageUnit::Microseconds
+            | UsageUnit::Nanoseconds => Some(Dimension::Time),
+            UsageUnit::CpuNanoseconds => Some(Dimension::CpuTime),
+            UsageUnit::Bytes | UsageUnit::Kibibytes => Some(Dimension::Size),
+            UsageUnit::BytesSec => Some(Dimension::SizeTime),
+            UsageUnit::Count | UsageUnit::Requests => Some(Dimension::Count),
+            UsageUnit::Custom(name) => UsageUnit::builtin(name)?.dimension(),
+        }
+    }
+
+    /// Returns the built-in unit called `name`, if any.
+    fn builtin(name: &str) -> Option<UsageUnit> {
+        match UsageUnit::from(name) {
+            UsageUnit::Custom(_) => None,
+            unit => Some(unit),
+        }
+    }
+
+    /// The canonical unit of the dimension of a built-in unit, and how
+    /// many of those make one of this unit. `None` if the unit already
+    /// is canonical.
+    fn base(&self) -> Option<(UsageUnit, u64)> {
+        match self {
+            UsageUnit::Seconds => Some((UsageUnit::Nanoseconds, 1_000_000_000)),
+            UsageUnit::Milliseconds => Some((UsageUnit::Nanoseconds, 1_000_000)),
+            UsageUnit::Microseconds => Some((UsageUnit::Nanoseconds, 1_000)),
+            UsageUnit::Kibibytes => Some((UsageUnit::Bytes, 1024)),
+            UsageUnit::Custom(name) => UsageUnit::builtin(name)?.base(),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for UsageUnit {
+    fn from(name: &str) -> Self {
+        match name {
+            "milliseconds" => UsageUnit::Milliseconds,
+            "bytes" => UsageUnit::Bytes,
+            "bytes_sec" => UsageUnit::BytesSec,
+            "microseconds" => UsageUnit::Microseconds,
+            "nanoseconds" => UsageUnit::Nanoseconds,
+            "cpu_nanoseconds" => UsageUnit::CpuNanoseconds,
+            "count" => UsageUnit::Count,
+            "requests" => UsageUnit::Requests,
+            "seconds" => UsageUnit::Seconds,
+            "kibibytes" => UsageUnit::Kibibytes,
+            _ => UsageUnit::Custom(name.to_string()),
+        }
+    }
+}
+
+impl PartialEq for UsageUnit {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for UsageUnit {}
+
+impl Hash for UsageUnit {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state);
+    }
+}
+
+impl fmt::Display for UsageUnit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for UsageUnit {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for UsageUnit {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Ok(UsageUnit::from(name.as_str()))
+    }
+}
+
+/// The physical quantity a unit measures. Amounts can only be
+/// compared or converted between units of the same dimension.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Dimension {
+    /// Wall time.
+    Time,
+    /// Time spent on a CPU.
+    CpuTime,
+    /// An amount of data.
+    Size,
+    /// An amount of data held over time.
+    SizeTime,
+    /// A number of occurrences.
+    Count,
+}
+
+/// Declares the dimension of every unit, including the custom ones
+/// an application uses, so amounts can be validated and converted.
+///
+/// Passed to `with_unit_normalization`, it makes the accountants
+/// convert usage to the canonical unit of its dimension before
+/// accumulating it: time to nanoseconds and sizes to bytes. The
+/// aggregation configured for the canonical unit then applies.
+#[derive(Clone, Debug, Default)]
+pub struct UnitRegistry {
+    custom: HashMap<String, Dimension>,
+    conversions: HashMap<String, (UsageUnit, u64)>,
+}
+
+impl UnitRegistry {
+    /// Creates a registry knowing the built-in units only.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declares the dimension of a custom unit. Built-in units keep
+    /// their own dimension.
+    pub fn with_unit(mut self, name: &str, dimension: Dimension) -> Self {
+        self.custom.insert(name.to_string(), dimension);
+        self
+    }
+
+    /// Declares a custom unit worth `factor` of the `base` unit. The
+    /// base can be a custom unit with its own conversion, conversions
+    /// are then chained.
+    ///
+    /// The custom unit gets the dimension of `base`. Fails if the chain
+    /// starting at `base` leads back to `name`, as such a unit has no
+    /// canonical unit to be converted to.
+    pub fn with_conversion(
+        mut self,
+        name: &str,
+        base: UsageUnit,
+        factor: u64,
+    ) -> Result<Self, ConversionCycle> {
+        let mut next = Some(&base);
+        while let Some(unit) = next {
+            if unit.as_str() == name {
+                return Err(ConversionCycle {
+                    unit: name.to_string(),
+                });
+            }
+            next = self.conversion(unit).map(|(base, _)| base);
+        }
+
+        if let Some(dimension) = self.dimension(&base) {
+            self.custom.insert(name.to_string(), dimension);
+        }
+        self.conversions.insert(name.to_string(), (base, factor));
+        Ok(self)
+    }
+
+    /// Converts an amount to the canonical unit of its dimension.
+    ///
+    /// Conversions only ever multiply by an integer factor, so they are
+    /// lossless. Units without a conversion are returned unchanged.
+    /// Returns `None` if the converted amount does not fit in a `u64`.
+    pub fn normalize(&self, mut amount: u64, mut unit: UsageUnit) -> Option<(u64, UsageUnit)> {
+        // `with_conversion` refuses cycles, so every chain ends.
+        while let Some((base, factor)) = self.conversion(&unit) {
+            amount = amount.checked_mul(*factor)?;
+            unit = base.clone();
+        }
+        match unit.base() {
+            Some((base, factor)) => Some((amount.checked_mul(factor)?, base)),
+            None => Some((amount, unit)),
+        }
+    }
+
+    /// Returns the base and factor of a custom unit declared with
+    /// `with_conversion`.
+    fn conversion(&self, unit: &UsageUnit) -> Option<&(UsageUnit, u64)> {
+        match unit {
+            UsageUnit::Custom(name) => self.conversions.get(name),
+            _ => None,
+        }
+    }
+
+    /// Returns the dimension of `unit`, or `None` for a custom unit
+    /// that was not declared.
+    pub fn dimension(&self, unit: &UsageUnit) -> Option<Dimension> {
+        unit.dimension()
+            .or_else(|| self.custom.get(unit.as_str()).copied())
+    }
+
+    /// Returns true if the dimension of `unit` is known.
+    pub fn contains(&self, unit: &UsageUnit) -> bool {
+        self.dimension(unit).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serialization_is_stable() {
+        let units = [
+            (UsageUnit::Milliseconds, "\"milliseconds\""),
+            (UsageUnit::Bytes, "\"bytes\""),
+            (UsageUnit::BytesSec, "\"bytes_sec\""),
+            (UsageUnit::CpuNanoseconds, "\"cpu_nanoseconds\""),
+            (UsageUnit::Custom("tokens".to_string()), "\"tokens\""),
+        ];
+        for (unit, json) in units {
+            assert_eq!(serde_json::to_string(&unit).unwrap(), json);
+            assert_eq!(serde_json::from_str::<UsageUnit>(json).unwrap(), unit);
+            assert_eq!(format!("\"{unit}\""), json);
+        }
+    }
+
+    #[test]
+    fn test_registry() {
+        let registry = UnitRegistry::new().with_unit("tokens", Dimension::Count);
+        assert_eq!(
+            registry.dimension(&UsageUnit::Microseconds),
+            Some(Dimension::Time)
+        );
+        assert_eq!(
+            registry.dimension(&UsageUnit::Custom("tokens".to_string())),
+            Some(Dimension::Count)
+        );
+        assert!(!registry.contains(&UsageUnit::Custom("widgets".to_string())));
+    }
+
+    #[test]
+    fn test_custom_builtin_name() {
+        let custom = UsageUnit::Custom("milliseconds".to_string());
+        assert_eq!(custom, UsageUnit::Milliseconds);
+        let units = HashMap::from([(UsageUnit::Milliseconds, 1)]);
+        assert_eq!(units.get(&custom), Some(&1));
+        assert_eq!(custom.dimension(), Some(Dimension::Time));
+        assert_eq!(
+            UnitRegistry::new().normalize(3, custom),
+            Some((3_000_000, UsageUnit::Nanoseconds))
+        );
+    }
+
+    #[test]
+    fn test_normalize() {
+        let minutes = UsageUnit::Custom("minutes".to_string());
+        let registry = UnitRegistry::new()
+            .with_conversion("minutes", UsageUnit::Seconds, 60)
+            .unwrap();
+        assert_eq!(registry.dimension(&minutes), Some(Dimension::Time));
+
+        assert_eq!(
+            registry.normalize(3, UsageUnit::Milliseconds),
+            Some((3_000_000, UsageUnit::Nanoseconds))
+        );
+        assert_eq!(
+            registry.normalize(3, UsageUnit::Microseconds),
+            Some((3_000, UsageUnit::Nanoseconds))
+        );
+        assert_eq!(
+            registry.normalize(2, UsageUnit::Kibibytes),
+            Some((2048, UsageUnit::Bytes))
+        );
+        assert_eq!(
+            registry.normalize(2, minutes),
+            Some((120_000_000_000, UsageUnit::Nanoseconds))
+        );
+        assert_eq!(
+            registry.normalize(5, UsageUnit::BytesSec),
+            Some((5, UsageUnit::BytesSec))
+        );
+        assert_eq!(registry.normalize(u64::MAX, UsageUnit::Seconds), None);
+    }
+
+    #[test]
+    fn test_normalize_chained_conversions() {
+        let hours = UsageUnit::Custom("hours".to_string());
+        let registry = UnitRegistry::new()
+            .with_conversion("minutes", UsageUnit::Seconds, 60)
+            .and_then(|units| {
+                units.with_conversion("hours", UsageUnit::Custom("minutes".to_string()), 60)
+            })
+            .unwrap();
+        assert_eq!(registry.dimension(&hours), Some(Dimension::Time));
+        assert_eq!(
+            registry.normalize(1, hours.clone()),
+            Some((3_600_000_000_000, UsageUnit::Nanoseconds))
+        );
+        // Every step of the chain is checked for overflow.
+        assert_eq!(registry.normalize(u64::MAX / 3000, hours), None);
+    }
+
+    #[test]
+    fn test_conversion_cycles() {
+        let registry = UnitRegistry::new()
+            .with_conversion("a", UsageUnit::Custom("b".to_string()), 2)
+            .and_then(|units| units.with_conversion("b", UsageUnit::Custom("c".to_string()), 3))
+            .unwrap();
+        let error = registry
+            .clone()
+            .with_conversion("c", UsageUnit::Custom("a".to_string()), 4)
+            .unwrap_err();
+        assert_eq!(error.unit, "c");
+        assert!(registry
+            .clone()
+            .with_conversion("d", UsageUnit::Custom("d".to_string()), 5)
+            .is_err());
+
+        // Redeclaring a unit of the chain can still end the chain.
+        let registry = registry
+            .with_conversion("b", UsageUnit::Seconds, 3)
+            .unwrap();
+        assert_eq!(
+            registry.normalize(1, UsageUnit::Custom("a".to_string())),
+            Some((6_000_000_000, UsageUnit::Nanoseconds))
+        );
+    }
+}
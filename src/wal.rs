@@ -0,0 +1,346 @@
+//! This module contains an optional write-ahead log keeping the usage
+//! of a `UsageAccountant` on disk until it is delivered.
+//!
+//! Whatever is in the accumulator is lost if the process is killed
+//! before the batch is flushed. The log periodically writes a snapshot
+//! of the usage that was not delivered yet, so the next instance of
+//! the application can produce it.
+//!
+
+use chrono::{DateTime, Duration, Utc};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::accountant::Message;
+use crate::accumulator::UsageKey;
+use crate::DeliveryReport;
+
+const SNAPSHOT_FILE: &str = "usage.wal";
+
+/// Persists the usage of an accountant that was not delivered yet in a
+/// local directory.
+///
+/// The snapshot holds the batch being accumulated and the messages
+/// handed to the producer whose delivery it did not confirm yet. It is
+/// rewritten every `snapshot_interval` while usage
+/// is recorded and after every flush, by writing a new file and
+/// renaming it over the previous one, so a crash leaves either the old
+/// or the new snapshot.
+///
+/// Messages the producer reports it failed to deliver are put back in
+/// the batch, within the limits of the `RetryPolicy` of the accountant.
+/// Deliveries are tracked by the sequence numbers the producer reports
+/// through `Producer::take_deliveries`, so the producer must not be
+/// used by anything else.
+/// Usage recorded since the last snapshot and gauges that are still
+/// open are not persisted. Usage is produced at least once: a crash
+/// after a message was delivered but before the snapshot was rewritten
+/// produces it again.
+#[derive(Debug)]
+pub struct WriteAheadLog {
+    dir: PathBuf,
+    snapshot_interval: Duration,
+    delivery_timeout: std::time::Duration,
+    last_snapshot: Option<DateTime<Utc>>,
+    /// Entries handed to the producer whose delivery was not confirmed,
+    /// by sequence number.
+    in_flight: BTreeMap<u64, (UsageKey, u64)>,
+    /// The sequence number of the next entry handed to the producer.
+    next_sequence: u64,
+}
+
+impl WriteAheadLog {
+    /// Creates a log writing its snapshot in `dir`, which is created
+    /// if needed when the log is attached to an accountant.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            snapshot_interval: Duration::seconds(5),
+            delivery_timeout: std::time::Duration::ZERO,
+            last_snapshot: None,
+            in_flight: BTreeMap::new(),
+            next_sequence: 0,
+        }
+    }
+
+    /// Sets how often the snapshot is rewritten while usage is being
+    /// recorded. Five seconds by default.
+    pub fn with_snapshot_interval(mut self, snapshot_interval: Duration) -> Self {
+        self.snapshot_interval = snapshot_interval;
+        self
+    }
+
+    /// Sets how long every flush waits for the producer to confirm the
+    /// delivery of the messages handed to it. By default it does not
+    /// wait, and the messages are only dropped from the log at a later
+    /// flush, once the producer reports them delivered.
+    pub fn with_delivery_timeout(mut self, delivery_timeout: std::time::Duration) -> Self {
+        self.delivery_timeout = delivery_timeout;
+        self
+    }
+
+    pub(crate) fn delivery_timeout(&self) -> std::time::Duration {
+        self.delivery_timeout
+    }
+
+    /// Creates the directory of the log and returns the entries of the
+    /// snapshot left by the previous instance, if any.
+    pub(crate) fn open(&mut self) -> io::Result<Vec<(UsageKey, u64)>> {
+        fs::create_dir_all(&self.dir)?;
+        let file = match File::open(self.dir.join(SNAPSHOT_FILE)) {
+            Ok(file) => file,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(error) => return Err(error),
+        };
+
+        let mut entries = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let entry: SnapshotEntry = serde_json::from_str(&line)?;
+            entries.push(entry.into_entry()?);
+        }
+        Ok(entries)
+    }
+
+    /// Returns true if the snapshot is older than `snapshot_interval`.
+    pub(crate) fn snapshot_due(&self, current_time: DateTime<Utc>) -> bool {
+        match self.last_snapshot {
+            Some(last_snapshot) => current_time - last_snapshot >= self.snapshot_interval,
+            None => true,
+        }
+    }
+
+    /// Keeps entries handed to the producer, in the order it accepted
+    /// them, until their delivery is confirmed.
+    pub(crate) fn sent(&mut self, entries: impl IntoIterator<Item = (UsageKey, u64)>) {
+        for entry in entries {
+            self.in_flight.insert(self.next_sequence, entry);
+            self.next_sequence += 1;
+        }
+    }
+
+    /// Forgets the entries the producer delivered or failed to deliver,
+    /// and returns the failed ones so they can be sent again.
+    ///
+    /// Producers that do not report delivery are considered to deliver
+    /// synchronously.
+    pub(crate) fn confirm(&mut self, deliveries: Option<DeliveryReport>) -> Vec<(UsageKey, u64)> {
+        let Some(deliveries) = deliveries else {
+            self.in_flight.clear();
+            return Vec::new();
+        };
+        for sequence in &deliveries.delivered {
+            self.in_flight.remove(sequence);
+        }
+        deliveries
+            .failed
+            .iter()
+            .filter_map(|sequence| self.in_flight.remove(sequence))
+            .collect()
+    }
+
+    /// Replaces the snapshot with the entries of `batch` and the ones
+    /// whose delivery is not confirmed.
+    pub(crate) fn write<'a>(
+        &mut self,
+        batch: impl Iterator<Item = (&'a UsageKey, &'a u64)>,
+        current_time: DateTime<Utc>,
+    ) -> io::Result<()> {
+        let tmp_path = self.dir.join(format!("{SNAPSHOT_FILE}.tmp"));
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        for (key, amount) in batch {
+            write_entry(&mut writer, key, *amount)?;
+        }
+        for (key, amount) in self.in_flight.values() {
+            write_entry(&mut writer, key, *amount)?;
+        }
+        let file = writer.into_inner().map_err(|error| error.into_error())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, self.dir.join(SNAPSHOT_FILE))?;
+
+        self.last_snapshot = Some(current_time);
+        Ok(())
+    }
+}
+
+/// An entry of the snapshot, in the format of the produced messages
+/// with the full precision of the bucket timestamp.
+#[derive(Serialize, Deserialize)]
+struct SnapshotEntry {
+    #[serde(flatten)]
+    message: Message,
+    /// The nanoseconds of the bucket timestamp, which messages round
+    /// down to the second. Buckets finer than a second need them to
+    /// be recovered under the key live usage is recorded under.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    timestamp_nanos: u32,
+}
+
+impl SnapshotEntry {
+    fn new(key: &UsageKey, amount: u64) -> Self {
+        SnapshotEntry {
+            message: Message::new(key.clone(), amount),
+            timestamp_nanos: key.quantized_timestamp.timestamp_subsec_nanos(),
+        }
+    }
+
+    /// Turns the entry back into the entry of the batch it was built
+    /// from. Fails if the timestamp is out of range, which means the
+    /// snapshot is corrupt.
+    fn into_entry(self) -> io::Result<(UsageKey, u64)> {
+        let message = self.message;
+        let quantized_timestamp = DateTime::from_timestamp(message.timestamp, self.timestamp_nanos)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "invalid usage timestamp {}.{:09}",
+                        message.timestamp, self.timestamp_nanos
+                    ),
+                )
+            })?;
+        let key = UsageKey {
+            quantized_timestamp,
+            resource_id: message.shared_resource_id,
+            app_feature: message.app_feature,
+            unit: message.usage_unit,
+            tags: message.tags,
+            aggregation: message.aggregation,
+        };
+        Ok((key, message.amount))
+    }
+}
+
+fn is_zero(nanos: &u32) -> bool {
+    *nanos == 0
+}
+
+/// Writes an entry as a line of JSON.
+fn write_entry(writer: &mut impl Write, key: &UsageKey, amount: u64) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, &SnapshotEntry::new(key, amount))?;
+    writer.write_all(b"\n")
+}
+
+/// A directory for the write-ahead log of a test. It is named after
+/// the test and removed when the guard is dropped, even if the test
+/// fails.
+#[cfg(test)]
+pub(crate) struct TempDir(PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub(crate) fn new(test: &str) -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("usage-accountant-{test}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        TempDir(dir)
+    }
+
+    pub(crate) fn path(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use crate::{Aggregation, UsageUnit};
+
+    use super::*;
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let dir = TempDir::new("snapshot-round-trip");
+        let now = Utc.with_ymd_and_hms(2023, 10, 8, 22, 15, 25).unwrap();
+        let key = |app_feature: &str| UsageKey {
+            quantized_timestamp: Utc.with_ymd_and_hms(2023, 10, 8, 22, 15, 0).unwrap(),
+            resource_id: "resource_1".to_string(),
+            app_feature: app_feature.to_string(),
+            unit: UsageUnit::Bytes,
+            tags: BTreeMap::from([("region".to_string(), "us".to_string())]),
+            aggregation: Aggregation::Max,
+        };
+
+        let mut wal = WriteAheadLog::new(dir.path());
+        assert!(wal.open().unwrap().is_empty());
+        assert!(wal.snapshot_due(now));
+
+        wal.sent([(key("transactions"), 100)]);
+        let batch = [(key("spans"), 200)];
+        wal.write(batch.iter().map(|(key, amount)| (key, amount)), now)
+            .unwrap();
+        assert!(!wal.snapshot_due(now + Duration::seconds(1)));
+
+        let mut entries = WriteAheadLog::new(dir.path()).open().unwrap();
+        entries.sort_by_key(|(_, amount)| *amount);
+        assert_eq!(
+            entries,
+            vec![(key("transactions"), 100), (key("spans"), 200)]
+        );
+
+        // Sequence numbers 1 to 3.
+        wal.sent([(key("spans"), 1), (key("spans"), 2), (key("spans"), 3)]);
+        let deliveries = DeliveryReport {
+            delivered: vec![0, 3],
+            failed: vec![2],
+        };
+        assert_eq!(wal.confirm(Some(deliveries)), vec![(key("spans"), 2)]);
+        wal.write(std::iter::empty(), now).unwrap();
+        assert_eq!(
+            WriteAheadLog::new(dir.path()).open().unwrap(),
+            vec![(key("spans"), 1)]
+        );
+
+        assert!(wal.confirm(None).is_empty());
+        wal.write(std::iter::empty(), now).unwrap();
+        assert!(WriteAheadLog::new(dir.path()).open().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_keeps_subsecond_buckets() {
+        let dir = TempDir::new("snapshot-keeps-subsecond-buckets");
+        let key = UsageKey {
+            quantized_timestamp: Utc.with_ymd_and_hms(2023, 10, 8, 22, 15, 0).unwrap()
+                + Duration::milliseconds(250),
+            resource_id: "resource_1".to_string(),
+            app_feature: "transactions".to_string(),
+            unit: UsageUnit::Bytes,
+            tags: BTreeMap::new(),
+            aggregation: Aggregation::Sum,
+        };
+
+        let mut wal = WriteAheadLog::new(dir.path());
+        assert!(wal.open().unwrap().is_empty());
+        let batch = [(key.clone(), 100)];
+        wal.write(batch.iter().map(|(key, amount)| (key, amount)), Utc::now())
+            .unwrap();
+        assert_eq!(
+            WriteAheadLog::new(dir.path()).open().unwrap(),
+            vec![(key, 100)]
+        );
+
+        // A timestamp out of range is a corrupt snapshot.
+        fs::write(
+            dir.path().join(SNAPSHOT_FILE),
+            r#"{"timestamp":9223372036854775807,"shared_resource_id":"resource_1","app_feature":"transactions","usage_unit":"bytes","amount":100}"#,
+        )
+        .unwrap();
+        let error = WriteAheadLog::new(dir.path()).open().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}